@@ -0,0 +1,7 @@
+//! The C interface of the `closefds` crate, which `include/closefds.h` declares, built as a
+//! `cdylib` and a `staticlib`.
+//!
+//! The functions are defined by `closefds` with the `capi` feature. Nothing refers to the crate,
+//! so it has to be linked explicitly.
+
+extern crate closefds;
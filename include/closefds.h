@@ -0,0 +1,61 @@
+/*
+ * C interface of the closefds crate. Link against the `cdylib` or `staticlib` that the
+ * closefds-capi package builds (`cargo build -p closefds-capi`).
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+#ifndef CLOSEFDS_H
+#define CLOSEFDS_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Modes for closefds_prepare(), which decide what happens to the file descriptors that aren't
+ * kept. */
+
+/* Set FD_CLOEXEC, so that exec() closes them. */
+#define CLOSEFDS_CLOSE_ON_EXEC 0
+/* Close the ones that don't have FD_CLOEXEC set right away. */
+#define CLOSEFDS_CLOSE 1
+/* Close all of them right away, for child processes that don't call exec(). */
+#define CLOSEFDS_CLOSE_ALL 2
+
+/* Error codes of closefds_apply() with this bit set describe which step failed. The error
+ * number is CLOSEFDS_ERRNO(code), and the file descriptor involved - if any - is
+ * CLOSEFDS_ERROR_FD(code), which is 0x3ffff if there is none. Other error codes are plain error
+ * numbers. */
+#define CLOSEFDS_ERROR_BIT (1 << 30)
+#define CLOSEFDS_ERRNO(code) \
+    (((code) & CLOSEFDS_ERROR_BIT) ? (((code) >> 18) & 0xff) : (code))
+#define CLOSEFDS_ERROR_FD(code) ((code) & 0x3ffff)
+
+typedef struct closefds_prepared closefds_prepared;
+
+/* Prepare a plan in the parent process that keeps the num_keep_fds file descriptors at keep_fds
+ * and treats every other file descriptor according to mode. This allocates memory and opens the
+ * fd directory, so it must be called before fork(). Returns NULL and sets errno on failure. */
+closefds_prepared *closefds_prepare(const int *keep_fds, size_t num_keep_fds, int mode);
+
+/* Process the file descriptors of the calling process according to the plan. Call this in the
+ * child process, right after fork() or in the callback of clone(). It doesn't allocate memory
+ * or take any locks. Returns 0 on success and an error code otherwise, which is EINVAL if
+ * prepared is NULL - such as when closefds_prepare() failed. */
+int closefds_apply(const closefds_prepared *prepared);
+
+/* Free a plan in the process that created it. Calling this in a child process leaks the plan
+ * instead of freeing it. NULL is ignored. */
+void closefds_free(closefds_prepared *prepared);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CLOSEFDS_H */
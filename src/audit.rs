@@ -0,0 +1,250 @@
+use std::{
+    fmt, io, mem,
+    os::unix::{ffi::OsStrExt, io::RawFd},
+    path::{Path, PathBuf},
+};
+
+use crate::error;
+#[cfg(target_os = "linux")]
+use crate::{getdents::GetdentsDir, strategy::open_proc_fd_dir};
+#[cfg(not(target_os = "linux"))]
+use crate::{readdir::OpenDir, DEV_FD_DIR_NAME};
+
+/// What kind of file a file descriptor refers to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum FdKind {
+    /// A regular file.
+    File,
+    /// A directory.
+    Directory,
+    /// A socket.
+    Socket,
+    /// A pipe or a FIFO.
+    Pipe,
+    /// A character device, such as a terminal.
+    CharDevice,
+    /// A block device.
+    BlockDevice,
+    /// An `eventfd()`. Linux only.
+    EventFd,
+    /// A `memfd_create()` file. Linux only.
+    MemFd,
+    /// Any other anonymous inode, such as an `epoll` instance, a `signalfd()`, or a
+    /// `timerfd()`, with its name - for example, `[eventpoll]`. Linux only.
+    AnonInode(String),
+    /// Anything else.
+    Other,
+}
+
+/// An open file descriptor of the current process, as reported by `audit()`.
+#[derive(Clone, Debug)]
+pub struct FdInfo {
+    fd: RawFd,
+    cloexec: bool,
+    kind: FdKind,
+    target: Option<PathBuf>,
+    flags: Option<libc::c_int>,
+}
+
+impl FdInfo {
+    /// The file descriptor number.
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// Whether `FD_CLOEXEC` is set. File descriptors without it are inherited by every child
+    /// process that doesn't close them.
+    pub fn is_cloexec(&self) -> bool {
+        self.cloexec
+    }
+
+    /// What kind of file the file descriptor refers to.
+    pub fn kind(&self) -> &FdKind {
+        &self.kind
+    }
+
+    /// What the file descriptor refers to, such as the path of a file or `socket:[1234]`. This
+    /// is the target of `/proc/thread-self/fd/<fd>` on Linux and the result of `F_GETPATH` on
+    /// macOS, and `None` where neither is available.
+    pub fn target(&self) -> Option<&Path> {
+        self.target.as_deref()
+    }
+
+    /// The file status flags, such as `O_APPEND` or `O_NONBLOCK`, along with the access mode.
+    /// On Linux, these are the flags from `/proc/thread-self/fdinfo/<fd>`, which also include
+    /// `O_CLOEXEC`. Elsewhere, they are the result of `F_GETFL`.
+    pub fn flags(&self) -> Option<libc::c_int> {
+        self.flags
+    }
+}
+
+impl fmt::Display for FdInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "fd {}: {:?}{}",
+            self.fd,
+            self.kind,
+            if self.cloexec {
+                ""
+            } else {
+                " without FD_CLOEXEC"
+            }
+        )?;
+        if let Some(ref target) = self.target {
+            write!(f, " -> {}", target.display())?;
+        }
+        if let Some(flags) = self.flags {
+            write!(f, " (flags 0{:o})", flags)?;
+        }
+        Ok(())
+    }
+}
+
+/// List the open file descriptors of the current process.
+///
+/// This is meant for finding out where file descriptors without `FD_CLOEXEC` - which leak into
+/// every child process that doesn't use this crate - come from. The file descriptors are found
+/// the same way that the directory walking strategies find them, by reading
+/// `/proc/thread-self/fd/` on Linux and `/dev/fd/` elsewhere, and the file descriptor that is
+/// used to read the directory isn't included. File descriptors that are opened or closed by
+/// other threads at the same time may or may not be included.
+///
+/// # Example
+///
+/// ```no_run
+/// for fd in closefds::audit()? {
+///     if !fd.is_cloexec() {
+///         eprintln!("{}", fd);
+///     }
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn audit() -> io::Result<Vec<FdInfo>> {
+    let mut fds = Vec::new();
+    for_each_fd(|fd| {
+        fds.push(fd);
+        Ok(())
+    })
+    .map_err(error::decode)?;
+
+    // Skip file descriptors that were closed since the directory was read.
+    Ok(fds.into_iter().filter_map(fd_info).collect())
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn for_each_fd<F: FnMut(RawFd) -> io::Result<()>>(f: F) -> io::Result<()> {
+    open_proc_fd_dir(GetdentsDir::open)?.for_each_fd(f)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn for_each_fd<F: FnMut(RawFd) -> io::Result<()>>(f: F) -> io::Result<()> {
+    OpenDir::open(
+        std::ffi::CStr::from_bytes_with_nul(DEV_FD_DIR_NAME).expect("Invalid Path"),
+        0,
+    )?
+    .for_each_fd(f)
+}
+
+fn fd_info(fd: RawFd) -> Option<FdInfo> {
+    let fd_flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if fd_flags == -1 {
+        return None;
+    }
+
+    let mut stat = mem::MaybeUninit::<libc::stat>::uninit();
+    let mode = if unsafe { libc::fstat(fd, stat.as_mut_ptr()) } == 0 {
+        unsafe { stat.assume_init() }.st_mode & libc::S_IFMT
+    } else {
+        0
+    };
+    let target = target(fd);
+    let kind = match mode {
+        libc::S_IFREG if is_memfd(target.as_deref()) => FdKind::MemFd,
+        libc::S_IFREG => FdKind::File,
+        libc::S_IFDIR => FdKind::Directory,
+        libc::S_IFSOCK => FdKind::Socket,
+        libc::S_IFIFO => FdKind::Pipe,
+        libc::S_IFCHR => FdKind::CharDevice,
+        libc::S_IFBLK => FdKind::BlockDevice,
+        _ => match anon_inode(target.as_deref()) {
+            Some("[eventfd]") => FdKind::EventFd,
+            Some(name) => FdKind::AnonInode(name.to_owned()),
+            None => FdKind::Other,
+        },
+    };
+
+    Some(FdInfo {
+        fd,
+        cloexec: fd_flags & libc::FD_CLOEXEC != 0,
+        kind,
+        target,
+        flags: flags(fd),
+    })
+}
+
+fn is_memfd(target: Option<&Path>) -> bool {
+    target.is_some_and(|target| target.as_os_str().as_bytes().starts_with(b"/memfd:"))
+}
+
+fn anon_inode(target: Option<&Path>) -> Option<&str> {
+    target?.to_str()?.strip_prefix("anon_inode:")
+}
+
+/// Call `read` with `/proc/thread-self/<dir>/<fd>`, or with `/proc/self/<dir>/<fd>` if that
+/// doesn't exist, like `open_proc_fd_dir()` does. A thread that called `unshare(CLONE_FILES)` has
+/// a file descriptor table of its own, which only the first path refers to.
+#[cfg(target_os = "linux")]
+fn read_proc_fd_file<T, F>(dir: &str, fd: RawFd, read: F) -> Option<T>
+where
+    F: Fn(String) -> io::Result<T>,
+{
+    read(format!("/proc/thread-self/{}/{}", dir, fd))
+        .or_else(|err| {
+            if err.kind() == io::ErrorKind::NotFound {
+                read(format!("/proc/self/{}/{}", dir, fd))
+            } else {
+                Err(err)
+            }
+        })
+        .ok()
+}
+
+#[cfg(target_os = "linux")]
+fn target(fd: RawFd) -> Option<PathBuf> {
+    read_proc_fd_file("fd", fd, std::fs::read_link)
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn target(fd: RawFd) -> Option<PathBuf> {
+    let mut buf = [0u8; libc::PATH_MAX as usize];
+    if unsafe { libc::fcntl(fd, libc::F_GETPATH, buf.as_mut_ptr()) } == -1 {
+        return None;
+    }
+    let path = std::ffi::CStr::from_bytes_until_nul(&buf).ok()?;
+    Some(PathBuf::from(std::ffi::OsStr::from_bytes(path.to_bytes())))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "ios")))]
+fn target(_fd: RawFd) -> Option<PathBuf> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn flags(fd: RawFd) -> Option<libc::c_int> {
+    let fdinfo = read_proc_fd_file("fdinfo", fd, std::fs::read)?;
+    fdinfo.split(|&b| b == b'\n').find_map(|line| {
+        let value = line.strip_prefix(b"flags:")?;
+        let value = std::str::from_utf8(value).ok()?.trim();
+        libc::c_int::from_str_radix(value, 8).ok()
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn flags(fd: RawFd) -> Option<libc::c_int> {
+    match unsafe { libc::fcntl(fd, libc::F_GETFL) } {
+        -1 => None,
+        flags => Some(flags),
+    }
+}
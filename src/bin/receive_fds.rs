@@ -0,0 +1,32 @@
+use std::{env, os::unix::io::AsRawFd};
+
+use closefds::{Receiver, MANIFEST_VAR};
+
+// Prints the received file descriptors as "name=fd", followed by whether
+// FD_CLOEXEC is set on them.
+fn main() {
+    let receiver = Receiver::new();
+    let fds = match env::args().nth(1).as_deref() {
+        Some("listen") => receiver.listen_fds(),
+        _ => receiver.manifest(MANIFEST_VAR),
+    };
+    let fds = match fds {
+        Ok(fds) => fds,
+        Err(err) => {
+            println!("error: {}", err);
+            return;
+        }
+    };
+
+    for (name, fd) in fds.into_vec() {
+        let fd_flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) };
+        println!(
+            "{}={} {}",
+            name,
+            fd.as_raw_fd(),
+            fd_flags & libc::FD_CLOEXEC != 0
+        );
+    }
+    assert!(env::var_os("LISTEN_FDS").is_none());
+    assert!(env::var_os(MANIFEST_VAR).is_none());
+}
@@ -7,7 +7,7 @@ use std::{
 };
 
 fn main() {
-    let args: Vec<String> = env::args().into_iter().collect();
+    let args: Vec<String> = env::args().collect();
 
     let w1: libc::c_int = args[1].parse().unwrap();
     let r1: libc::c_int = args[2].parse().unwrap();
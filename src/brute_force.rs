@@ -0,0 +1,41 @@
+use std::{io, os::unix::io::RawFd};
+
+use crate::error::{self, Phase};
+
+/// Call `f` with every open file descriptor below the soft `RLIMIT_NOFILE` limit.
+///
+/// File descriptors that were opened before the limit was lowered may be above
+/// it and will be missed, which is why this is never picked automatically.
+pub(crate) fn for_each_fd<F>(mut f: F) -> io::Result<()>
+where
+    F: FnMut(RawFd) -> io::Result<()>,
+{
+    for fd in 0..fd_limit()? {
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EBADF) {
+                continue;
+            }
+            return Err(error::last_os_error(Phase::Fcntl, Some(fd)));
+        }
+        f(fd)?;
+    }
+    Ok(())
+}
+
+/// The soft `RLIMIT_NOFILE` limit - no file descriptor at or above it can be opened.
+pub(crate) fn fd_limit() -> io::Result<RawFd> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == -1 {
+        return Err(error::last_os_error(Phase::FdLimit, None));
+    }
+
+    if limit.rlim_cur > RawFd::MAX as libc::rlim_t {
+        Ok(RawFd::MAX)
+    } else {
+        Ok(limit.rlim_cur as RawFd)
+    }
+}
@@ -0,0 +1,343 @@
+use std::{
+    cell::UnsafeCell,
+    io,
+    marker::PhantomData,
+    ops::RangeBounds,
+    os::unix::{
+        io::{AsFd, AsRawFd, BorrowedFd, RawFd},
+        process::CommandExt,
+    },
+    process::{Child, Command},
+    sync::Arc,
+};
+
+use crate::{
+    error,
+    keep::{self, KeepFds},
+    posix_spawn::{self, SpawnedChild},
+    remap::Remap,
+    CloseFds, Mode, Program, Strategy,
+};
+
+impl CloseFds {
+    /// Create a builder to configure which file descriptors to keep open across `exec()`.
+    pub fn builder() -> CloseFdsBuilder<'static> {
+        CloseFdsBuilder::default()
+    }
+}
+
+/// A builder for the closure that is passed as a `pre_exec()` function.
+///
+/// By default, no file descriptors are kept - not even STDIN, STDOUT, and STDERR - and
+/// `Strategy::Auto` and `Mode::CloseOnExec` are used.
+///
+/// # Example
+///
+/// The following example will spawn a child process that inherits STDIN, STDOUT, STDERR, and
+/// file descriptors 10 through 19.
+///
+/// ```no_run
+/// # use closefds::CloseFds;
+/// # use std::process::Command;
+/// # use std::os::unix::process::CommandExt;
+/// # fn main() -> std::io::Result<()> {
+/// let close_fds = CloseFds::builder()
+///     .keep_stdio(true)
+///     .keep_range(10..20)
+///     .build()?;
+/// # unsafe {
+/// Command::new("path/to/program")
+///     .pre_exec(close_fds)
+///     .spawn()
+///     .expect("Spawn Failed");
+/// # }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Borrowed File Descriptors
+///
+/// File descriptors that are kept with `keep_fd()` or `keep_borrowed()` are borrowed for the
+/// lifetime `'fd`, and so is the closure that `build()` creates. This makes sure that those file
+/// descriptors can't be closed - and their numbers reused by unrelated files - before the child
+/// process has been spawned. Since `Command::pre_exec()` only accepts closures that don't borrow
+/// anything, use `spawn()` to spawn a `Command` with such a builder:
+///
+/// ```no_run
+/// # use closefds::CloseFds;
+/// # use std::{fs::File, process::Command};
+/// # fn main() -> std::io::Result<()> {
+/// let log = File::create("log.txt")?;
+/// let mut cmd = Command::new("path/to/program");
+/// let child = CloseFds::builder()
+///     .keep_stdio(true)
+///     .keep_fd(&log)
+///     .spawn(&mut cmd)?;
+/// // `log` can't be dropped before this point.
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CloseFdsBuilder<'fd> {
+    keep_fds: Vec<RawFd>,
+    keep_ranges: Vec<(RawFd, RawFd)>,
+    keep_stdio: bool,
+    remap: Vec<(RawFd, RawFd)>,
+    strategy: Strategy,
+    mode: Mode,
+    borrowed_fds: PhantomData<BorrowedFd<'fd>>,
+}
+
+impl<'fd> CloseFdsBuilder<'fd> {
+    /// Keep `fd` open across `exec()`.
+    pub fn keep(mut self, fd: RawFd) -> Self {
+        self.keep_fds.push(fd);
+        self
+    }
+
+    /// Keep the file descriptor of `fd` open across `exec()`. `fd` stays borrowed for as long as
+    /// the builder and the closure it creates exist.
+    pub fn keep_fd<F: AsFd + ?Sized>(self, fd: &'fd F) -> Self {
+        self.keep_borrowed(fd.as_fd())
+    }
+
+    /// Keep `fd` open across `exec()`. `fd` stays borrowed for as long as the builder and the
+    /// closure it creates exist.
+    pub fn keep_borrowed(self, fd: BorrowedFd<'fd>) -> Self {
+        self.keep(fd.as_raw_fd())
+    }
+
+    /// Keep every file descriptor in `range` open across `exec()`.
+    ///
+    /// `range` may be unbounded - for example, `..10` keeps every file descriptor below 10 and
+    /// `10..` keeps every file descriptor from 10 up.
+    ///
+    /// Unlike with `keep()`, the `FD_CLOEXEC` flag isn't cleared on the file descriptors in
+    /// `range` - they are just left alone, so the ones that have it set are still closed on
+    /// `exec()`. Otherwise, a range would also keep file descriptors that are private to the
+    /// parent process open, such as the pipe that `Command` uses to report `exec()` errors.
+    pub fn keep_range<R: RangeBounds<RawFd>>(mut self, range: R) -> Self {
+        self.keep_ranges.push(keep::to_inclusive(&range));
+        self
+    }
+
+    /// Move `from` to `to` in the child process, before the open file descriptors are
+    /// processed.
+    ///
+    /// `to` is kept open across `exec()`, while `from` is treated like any other file
+    /// descriptor - it is only kept if it is kept explicitly. The moves don't depend on each
+    /// other, so file descriptors can be swapped or moved in cycles. Every `to` may only be used
+    /// once.
+    pub fn remap(mut self, from: RawFd, to: RawFd) -> Self {
+        self.remap.push((from, to));
+        self
+    }
+
+    /// Move the file descriptor of `fd` to `to` in the child process, like `remap()` does. `fd`
+    /// stays borrowed for as long as the builder and the closure it creates exist.
+    pub fn remap_fd<F: AsFd + ?Sized>(self, fd: &'fd F, to: RawFd) -> Self {
+        self.remap(fd.as_fd().as_raw_fd(), to)
+    }
+
+    /// Whether to keep STDIN, STDOUT, and STDERR open across `exec()`.
+    pub fn keep_stdio(mut self, keep_stdio: bool) -> Self {
+        self.keep_stdio = keep_stdio;
+        self
+    }
+
+    /// The `Strategy` to use to find the open file descriptors.
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// What to do with the file descriptors that aren't kept open.
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Create the closure. It behaves just like the closure that is returned by
+    /// `close_fds_on_exec()`, and the same requirements apply to it.
+    pub fn build(self) -> io::Result<impl FnMut() -> io::Result<()> + Send + Sync + 'fd> {
+        let mut close_fds = self.build_close_fds()?;
+
+        let func = move || close_fds.before_exec();
+
+        Ok(func)
+    }
+
+    /// Spawn `cmd` with the closure that `build()` creates as a `pre_exec()` function.
+    ///
+    /// This uses up `cmd`. `Command` has no way to remove a `pre_exec()` function, so the
+    /// closure stays installed in `cmd`, but it only does anything while this function runs.
+    /// Spawning `cmd` again later - when the borrowed file descriptors may have been closed -
+    /// fails with an `EBADF` error, whether that is with `Command::spawn()` or with this crate.
+    /// To restart a process, spawn a new `Command`. This fails with `Mode::CloseAll`, which would
+    /// close the pipe that `Command` uses to report `exec()` errors.
+    pub fn spawn(self, cmd: &mut Command) -> io::Result<Child> {
+        self.spawn_with(cmd, || Ok(()))
+    }
+
+    /// Like `spawn()`, but also calls `after` in the child process once the file descriptors
+    /// have been processed.
+    pub(crate) fn spawn_with<G>(self, cmd: &mut Command, after: G) -> io::Result<Child>
+    where
+        G: FnMut() -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.run_with(cmd, after, Command::spawn)
+    }
+
+    /// Install the closure in `cmd` while `run` runs, followed by `after`.
+    pub(crate) fn run_with<G, R, T>(self, cmd: &mut Command, after: G, run: R) -> io::Result<T>
+    where
+        G: FnMut() -> io::Result<()> + Send + Sync + 'static,
+        R: FnOnce(&mut Command) -> io::Result<T>,
+    {
+        let (armed, func) = self.armed_hook(after)?;
+
+        // The closure only uses functions that are safe to call after fork().
+        unsafe {
+            cmd.pre_exec(func);
+        }
+        let result = run(cmd);
+        drop(armed);
+        result.map_err(error::decode)
+    }
+
+    /// Create a closure for a `pre_exec()` function that processes the file descriptors and then
+    /// calls `after`, but only until the returned `Armed` is dropped.
+    ///
+    /// Afterwards, the closure stays installed in the `Command`, but the plan is dropped, and the
+    /// closure fails with `EBADF` whenever the `Command` is spawned again.
+    pub(crate) fn armed_hook<G>(
+        self,
+        after: G,
+    ) -> io::Result<(
+        Armed<G>,
+        impl FnMut() -> io::Result<()> + Send + Sync + 'static,
+    )>
+    where
+        G: FnMut() -> io::Result<()> + Send + Sync + 'static,
+    {
+        if self.mode == Mode::CloseAll {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Mode::CloseAll can't be used to spawn a Command",
+            ));
+        }
+
+        let close_fds = self.build_close_fds()?;
+
+        let armed = Armed {
+            plan: Arc::new(Plan(UnsafeCell::new(Some((close_fds, after))))),
+        };
+        let func = {
+            let plan = armed.plan.clone();
+            move || {
+                // The parent process only takes the plan out once the Command has been spawned.
+                match unsafe { &mut *plan.0.get() } {
+                    Some((close_fds, after)) => {
+                        close_fds.before_exec()?;
+                        after()
+                    }
+                    None => Err(io::Error::from_raw_os_error(libc::EBADF)),
+                }
+            }
+        };
+
+        Ok((armed, func))
+    }
+
+    /// Spawn `program` with `posix_spawn()` instead of `fork()` if possible, and like `spawn()`
+    /// otherwise.
+    ///
+    /// Installing a `pre_exec()` function makes `Command` use `fork()`, which copies the page
+    /// tables of the parent process and so is slow for large processes. `posix_spawn()` can't
+    /// run any code in the child process, though. Instead, this closes every file descriptor
+    /// above the highest kept one with `posix_spawn_file_actions_addclosefrom_np()`, which is
+    /// available in glibc 2.34 and later and in FreeBSD 13.1 and later. So `posix_spawn()` is
+    /// only used if the kept file descriptors - including the targets of `remap()` - form a
+    /// single range that starts at 0, such as STDIN, STDOUT, and STDERR and some file
+    /// descriptors remapped to 3 and up. The strategy is ignored in that case.
+    ///
+    /// Either way, the child process runs `program` with exactly the settings of the `Program`.
+    /// Its standard streams are the file descriptors 0, 1, and 2 of the plan, so use `remap()` to
+    /// redirect them.
+    pub fn posix_spawn(self, program: &Program) -> io::Result<SpawnedChild> {
+        if self.mode != Mode::CloseAll {
+            let (keep_fds, remap) = self.keep_fds_and_remap()?;
+            if let Some(result) = posix_spawn::spawn(program, &keep_fds, remap.fds()) {
+                return result;
+            }
+        }
+        self.spawn(&mut program.command()).map(SpawnedChild::from)
+    }
+
+    /// Spawn `program` in a child process that is created with `clone3()` - or `clone()` on
+    /// older kernels - with `CLONE_VM` and `CLONE_VFORK`, like `vfork()` does. Linux only.
+    ///
+    /// The child process shares the memory of the parent process, so nothing is copied, but it
+    /// still processes the file descriptors itself before it calls `execve()`, with the same
+    /// guarantees as the closure that `build()` creates. All signals are blocked in the calling
+    /// thread while the child process runs, and the child process resets every signal handler
+    /// before it unblocks them again. Errors - including `execve()` errors - are returned by this
+    /// function. Like with `posix_spawn()`, the child process runs `program` with exactly the
+    /// settings of the `Program`, and its standard streams are the file descriptors 0, 1, and 2
+    /// of the plan.
+    pub fn vfork_spawn(self, program: &Program) -> io::Result<SpawnedChild> {
+        #[cfg(target_os = "linux")]
+        {
+            let mut close_fds = self.build_close_fds()?;
+            crate::vfork::spawn(program, &mut close_fds)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = program;
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "vfork_spawn() is only available on Linux",
+            ))
+        }
+    }
+
+    pub(crate) fn build_close_fds(self) -> io::Result<CloseFds> {
+        let (keep_fds, remap) = self.keep_fds_and_remap()?;
+        CloseFds::new(keep_fds, remap, self.strategy, self.mode)
+    }
+
+    fn keep_fds_and_remap(&self) -> io::Result<(KeepFds, Remap)> {
+        let remap = Remap::new(self.remap.clone())?;
+
+        let mut keep_fds = self.keep_fds.clone();
+        if self.keep_stdio {
+            keep_fds.extend(0..=2);
+        }
+        keep_fds.extend(remap.targets());
+        let keep_fds = KeepFds::new(keep_fds, self.keep_ranges.clone());
+
+        Ok((keep_fds, remap))
+    }
+}
+
+/// The plan of a closure created by `CloseFdsBuilder::armed_hook()`.
+struct Plan<G>(UnsafeCell<Option<(CloseFds, G)>>);
+
+// The parent process only takes the plan out when the Armed is dropped, which is never while
+// the Command is being spawned, and child processes only use their own copy of it.
+unsafe impl<G: Send> Send for Plan<G> {}
+unsafe impl<G: Send> Sync for Plan<G> {}
+
+/// Arms the closure that was created by `CloseFdsBuilder::armed_hook()` and drops its plan when
+/// dropped.
+pub(crate) struct Armed<G> {
+    plan: Arc<Plan<G>>,
+}
+
+impl<G> Drop for Armed<G> {
+    fn drop(&mut self) {
+        // Frees the buffer or closes the directory right away, rather than when the Command is
+        // dropped.
+        drop(unsafe { (*self.plan.0.get()).take() });
+    }
+}
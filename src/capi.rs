@@ -0,0 +1,75 @@
+//! The C interface that is declared in `include/closefds.h`.
+
+use std::{io, os::raw::c_int, ptr, slice};
+
+use crate::{error, CloseFds, Mode, Prepared};
+
+const CLOSEFDS_CLOSE_ON_EXEC: c_int = 0;
+const CLOSEFDS_CLOSE: c_int = 1;
+const CLOSEFDS_CLOSE_ALL: c_int = 2;
+
+/// Prepare a plan that keeps the `num_keep_fds` file descriptors at `keep_fds` and treats every
+/// other file descriptor according to `mode`. Returns `NULL` and sets `errno` on failure.
+#[no_mangle]
+pub unsafe extern "C" fn closefds_prepare(
+    keep_fds: *const c_int,
+    num_keep_fds: usize,
+    mode: c_int,
+) -> *mut Prepared<'static> {
+    match prepare(keep_fds, num_keep_fds, mode) {
+        Ok(prepared) => Box::into_raw(Box::new(prepared)),
+        Err(err) => {
+            errno::set_errno(errno::Errno(error::errno(&err).unwrap_or(libc::EINVAL)));
+            ptr::null_mut()
+        }
+    }
+}
+
+unsafe fn prepare(
+    keep_fds: *const c_int,
+    num_keep_fds: usize,
+    mode: c_int,
+) -> io::Result<Prepared<'static>> {
+    let mode = match mode {
+        CLOSEFDS_CLOSE_ON_EXEC => Mode::CloseOnExec,
+        CLOSEFDS_CLOSE => Mode::Close,
+        CLOSEFDS_CLOSE_ALL => Mode::CloseAll,
+        _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+    };
+    let keep_fds = if num_keep_fds == 0 {
+        &[]
+    } else if keep_fds.is_null() {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    } else {
+        slice::from_raw_parts(keep_fds, num_keep_fds)
+    };
+
+    let builder = keep_fds
+        .iter()
+        .fold(CloseFds::builder().mode(mode), |builder, &fd| {
+            builder.keep(fd)
+        });
+    Prepared::new(builder)
+}
+
+/// Process the file descriptors of the calling process according to `prepared`. Returns 0 on
+/// success and an error code otherwise, which is `EINVAL` if `prepared` is `NULL`.
+#[no_mangle]
+pub unsafe extern "C" fn closefds_apply(prepared: *const Prepared<'static>) -> c_int {
+    if prepared.is_null() {
+        return libc::EINVAL;
+    }
+    match (*prepared).apply_in_child() {
+        Ok(()) => 0,
+        Err(errno::Errno(code)) => code,
+    }
+}
+
+/// Free a plan that was created by `closefds_prepare()`, unless this is a child process of the
+/// process that created it. `NULL` is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn closefds_free(prepared: *mut Prepared<'static>) {
+    if !prepared.is_null() && (*prepared).is_in_creating_process() {
+        drop(Box::from_raw(prepared));
+    }
+}
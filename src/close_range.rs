@@ -0,0 +1,37 @@
+use std::{io, os::unix::io::RawFd};
+
+use crate::error::{self, Phase};
+
+/// Close every open file descriptor from `first` to `last`, inclusive - or set `FD_CLOEXEC` on
+/// them if `flags` is `CLOSE_RANGE_CLOEXEC`.
+pub(crate) fn close_range(first: RawFd, last: libc::c_uint, flags: libc::c_uint) -> io::Result<()> {
+    let ret = unsafe { libc::syscall(libc::SYS_close_range, first as libc::c_uint, last, flags) };
+    if ret == -1 {
+        return Err(error::last_os_error(Phase::CloseRange, Some(first)));
+    }
+    Ok(())
+}
+
+// Kernels before 5.9 don't have close_range() (ENOSYS), kernels before 5.11
+// don't understand CLOSE_RANGE_CLOEXEC (EINVAL), and seccomp filters in
+// containers commonly reject syscalls they don't know about (EPERM). In all
+// of those cases, no file descriptors were touched and we can fall back to
+// walking the fd directory.
+pub(crate) fn is_unsupported(err: &io::Error) -> bool {
+    matches!(
+        error::errno(err),
+        Some(libc::ENOSYS) | Some(libc::EPERM) | Some(libc::EINVAL)
+    )
+}
+
+/// Check whether `close_range()` with `CLOSE_RANGE_CLOEXEC` works without
+/// modifying any file descriptors.
+pub(crate) fn probe() -> io::Result<()> {
+    // No file descriptor can have the number c_uint::MAX, so this range is
+    // always empty - but the kernel still validates the flags.
+    close_range(
+        libc::c_uint::MAX as RawFd,
+        libc::c_uint::MAX,
+        libc::CLOSE_RANGE_CLOEXEC,
+    )
+}
@@ -0,0 +1,123 @@
+use std::{
+    io,
+    os::unix::io::{AsFd, RawFd},
+    process::{Child, Command, ExitStatus, Output},
+};
+
+use crate::{CloseFds, CloseFdsBuilder, Mode, Strategy};
+
+/// An extension trait for `Command` that spawns child processes which only inherit the file
+/// descriptors that they are meant to, without any `unsafe` code.
+///
+/// # Example
+///
+/// The following example will spawn a child process that inherits STDIN, STDOUT, STDERR, the
+/// socket at its current file descriptor, and the log file as file descriptor 4.
+///
+/// ```no_run
+/// # use closefds::CommandExt;
+/// # use std::{fs::File, net::TcpListener, process::Command};
+/// # fn main() -> std::io::Result<()> {
+/// let sock = TcpListener::bind("127.0.0.1:8080")?;
+/// let log = File::create("log.txt")?;
+/// let child = Command::new("path/to/program")
+///     .arg("--verbose")
+///     .close_fds()
+///     .inherit_fd(&sock)
+///     .inherit_fd_as(&log, 4)
+///     .spawn()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// With the `tokio` feature, this is also implemented for `tokio::process::Command`.
+pub trait CommandExt: Sized {
+    /// Start planning which file descriptors the child process inherits. By default, it only
+    /// inherits STDIN, STDOUT, and STDERR.
+    fn close_fds(&mut self) -> CloseFdsCommand<'_, 'static, Self>;
+}
+
+impl CommandExt for Command {
+    fn close_fds(&mut self) -> CloseFdsCommand<'_, 'static, Self> {
+        CloseFdsCommand::new(self)
+    }
+}
+
+/// A `Command` together with the file descriptors that its child processes inherit, created by
+/// `CommandExt::close_fds()`.
+///
+/// The plan is installed as a single `pre_exec()` function, and only used while `spawn()`,
+/// `output()`, or `status()` runs, like `CloseFdsBuilder::spawn()` does. The inherited file
+/// descriptors stay borrowed until then. Like `CloseFdsBuilder::spawn()`, this uses up the
+/// `Command`: spawning it again fails with `EBADF`, so spawn a new `Command` to restart a
+/// process.
+#[derive(Debug)]
+pub struct CloseFdsCommand<'cmd, 'fd, C = Command> {
+    pub(crate) cmd: &'cmd mut C,
+    pub(crate) builder: CloseFdsBuilder<'fd>,
+}
+
+impl<'cmd, C> CloseFdsCommand<'cmd, 'static, C> {
+    pub(crate) fn new(cmd: &'cmd mut C) -> Self {
+        CloseFdsCommand {
+            cmd,
+            builder: CloseFds::builder().keep_stdio(true),
+        }
+    }
+}
+
+impl<'cmd, 'fd, C> CloseFdsCommand<'cmd, 'fd, C> {
+    /// Let the child process inherit `fd` at its current file descriptor number.
+    pub fn inherit_fd<F: AsFd + ?Sized>(self, fd: &'fd F) -> Self {
+        self.map_builder(|builder| builder.keep_fd(fd))
+    }
+
+    /// Let the child process inherit `fd` as file descriptor `to`.
+    pub fn inherit_fd_as<F: AsFd + ?Sized>(self, fd: &'fd F, to: RawFd) -> Self {
+        self.map_builder(|builder| builder.remap_fd(fd, to))
+    }
+
+    /// Whether the child process inherits STDIN, STDOUT, and STDERR. They are inherited by
+    /// default.
+    pub fn inherit_stdio(self, inherit_stdio: bool) -> Self {
+        self.map_builder(|builder| builder.keep_stdio(inherit_stdio))
+    }
+
+    /// The `Strategy` to use to find the open file descriptors.
+    pub fn strategy(self, strategy: Strategy) -> Self {
+        self.map_builder(|builder| builder.strategy(strategy))
+    }
+
+    /// What to do with the file descriptors that aren't inherited. Spawning fails with
+    /// `Mode::CloseAll`, which would close the pipe that `Command` uses to report `exec()` errors.
+    pub fn mode(self, mode: Mode) -> Self {
+        self.map_builder(|builder| builder.mode(mode))
+    }
+
+    fn map_builder<B>(self, f: B) -> Self
+    where
+        B: FnOnce(CloseFdsBuilder<'fd>) -> CloseFdsBuilder<'fd>,
+    {
+        CloseFdsCommand {
+            cmd: self.cmd,
+            builder: f(self.builder),
+        }
+    }
+}
+
+impl<'cmd, 'fd> CloseFdsCommand<'cmd, 'fd, Command> {
+    /// Spawn the command like `Command::spawn()` does.
+    pub fn spawn(self) -> io::Result<Child> {
+        self.builder.spawn(self.cmd)
+    }
+
+    /// Run the command and collect its output like `Command::output()` does.
+    pub fn output(self) -> io::Result<Output> {
+        self.builder.run_with(self.cmd, || Ok(()), Command::output)
+    }
+
+    /// Run the command and wait for it to exit like `Command::status()` does.
+    pub fn status(self) -> io::Result<ExitStatus> {
+        self.builder.run_with(self.cmd, || Ok(()), Command::status)
+    }
+}
@@ -0,0 +1,227 @@
+use std::{error, fmt, io, os::unix::io::RawFd};
+
+/// Set in every raw OS error code that encodes a `CloseFdsError`. No real error number is this
+/// large.
+const MARKER: i32 = 1 << 30;
+const PHASE_SHIFT: u32 = 26;
+const PHASE_MASK: i32 = 0xf;
+const ERRNO_SHIFT: u32 = 18;
+const ERRNO_MASK: i32 = 0xff;
+const FD_MASK: i32 = 0x3_ffff;
+/// The value of the fd bits for errors without a file descriptor, or with one that doesn't fit.
+const NO_FD: i32 = FD_MASK;
+
+/// The step of processing the file descriptors that failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Phase {
+    /// Opening the fd directory, such as `/proc/thread-self/fd/` or `/dev/fd/`.
+    OpenDir,
+    /// Rewinding the fd directory with `rewinddir()`.
+    Rewind,
+    /// Reading an entry of the fd directory.
+    ReadEntry,
+    /// Parsing the name of an entry of the fd directory as a file descriptor number.
+    ParseName,
+    /// Getting or setting the flags of a file descriptor with `fcntl()`.
+    Fcntl,
+    /// Closing a file descriptor.
+    Close,
+    /// Calling `close_range()` on a range of file descriptors that starts at the file descriptor.
+    CloseRange,
+    /// Moving a file descriptor that was passed to `CloseFdsBuilder::remap()`.
+    Remap,
+    /// Getting the `RLIMIT_NOFILE` limit.
+    FdLimit,
+}
+
+impl Phase {
+    const ALL: [Phase; 9] = [
+        Phase::OpenDir,
+        Phase::Rewind,
+        Phase::ReadEntry,
+        Phase::ParseName,
+        Phase::Fcntl,
+        Phase::Close,
+        Phase::CloseRange,
+        Phase::Remap,
+        Phase::FdLimit,
+    ];
+
+    // 0 isn't used, so that a code with the marker bit but no phase isn't mistaken for one.
+    fn code(self) -> i32 {
+        Phase::ALL
+            .iter()
+            .position(|&phase| phase == self)
+            .expect("Unknown Phase") as i32
+            + 1
+    }
+
+    fn from_code(code: i32) -> Option<Phase> {
+        Phase::ALL.get((code as usize).checked_sub(1)?).copied()
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Phase::OpenDir => "opening the fd directory",
+            Phase::Rewind => "rewinding the fd directory",
+            Phase::ReadEntry => "reading the fd directory",
+            Phase::ParseName => "parsing a name in the fd directory",
+            Phase::Fcntl => "calling fcntl()",
+            Phase::Close => "closing a file descriptor",
+            Phase::CloseRange => "calling close_range()",
+            Phase::Remap => "remapping a file descriptor",
+            Phase::FdLimit => "getting the RLIMIT_NOFILE limit",
+        })
+    }
+}
+
+/// An error that happened while the file descriptors were processed in the child process.
+///
+/// The closures created by this crate run after `fork()`, where an `io::Error` can't carry
+/// anything but an error number without allocating - and `Command` only passes that number on
+/// to the parent process anyway. So these errors are encoded as raw OS error codes that no real
+/// error number uses, and `from_io_error()` decodes them again in the parent process:
+///
+/// ```no_run
+/// # use closefds::{close_fds_on_exec, CloseFdsError};
+/// # use std::{os::unix::process::CommandExt, process::Command};
+/// # fn main() -> std::io::Result<()> {
+/// let mut cmd = Command::new("path/to/program");
+/// unsafe {
+///     cmd.pre_exec(close_fds_on_exec(vec![])?);
+/// }
+/// if let Err(err) = cmd.spawn() {
+///     if let Some(err) = CloseFdsError::from_io_error(&err) {
+///         eprintln!("{} (fd {:?})", err, err.fd());
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Functions of this crate that spawn child processes themselves, such as
+/// `CloseFdsBuilder::spawn()`, already decode them and return an `io::Error` that wraps the
+/// `CloseFdsError`, with the `ErrorKind` of its error number.
+///
+/// Error numbers above 255 and file descriptors above 262142 don't fit into the encoding. In
+/// that case, only the error number is reported, or the error is reported without a file
+/// descriptor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CloseFdsError {
+    phase: Phase,
+    errno: i32,
+    fd: Option<RawFd>,
+}
+
+impl CloseFdsError {
+    pub(crate) fn new(phase: Phase, errno: i32, fd: Option<RawFd>) -> Self {
+        CloseFdsError { phase, errno, fd }
+    }
+
+    /// The step that failed.
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// The error number.
+    pub fn errno(&self) -> i32 {
+        self.errno
+    }
+
+    /// The file descriptor that the failed step was working on, if any.
+    pub fn fd(&self) -> Option<RawFd> {
+        self.fd
+    }
+
+    /// The raw OS error code that encodes this error, or just the error number if it doesn't fit.
+    pub fn to_raw_os_error(&self) -> i32 {
+        if self.errno <= 0 || self.errno > ERRNO_MASK {
+            return self.errno;
+        }
+        let fd = match self.fd {
+            Some(fd) if (0..NO_FD).contains(&fd) => fd,
+            _ => NO_FD,
+        };
+        MARKER | (self.phase.code() << PHASE_SHIFT) | (self.errno << ERRNO_SHIFT) | fd
+    }
+
+    /// Decode a raw OS error code that was created by `to_raw_os_error()`. Returns `None` for
+    /// any other error code.
+    pub fn from_raw_os_error(code: i32) -> Option<CloseFdsError> {
+        if code & !(MARKER - 1) != MARKER {
+            return None;
+        }
+        let phase = Phase::from_code((code >> PHASE_SHIFT) & PHASE_MASK)?;
+        let errno = (code >> ERRNO_SHIFT) & ERRNO_MASK;
+        if errno == 0 {
+            return None;
+        }
+        let fd = match code & FD_MASK {
+            NO_FD => None,
+            fd => Some(fd),
+        };
+        Some(CloseFdsError::new(phase, errno, fd))
+    }
+
+    /// Get the `CloseFdsError` out of an `io::Error` - either one that was decoded by this crate
+    /// or one with an encoded raw OS error code.
+    pub fn from_io_error(err: &io::Error) -> Option<CloseFdsError> {
+        match err.raw_os_error() {
+            Some(code) => CloseFdsError::from_raw_os_error(code),
+            None => err.get_ref()?.downcast_ref::<CloseFdsError>().copied(),
+        }
+    }
+
+    /// The encoded form, which is safe to create after `fork()`.
+    pub(crate) fn into_raw(self) -> io::Error {
+        io::Error::from_raw_os_error(self.to_raw_os_error())
+    }
+}
+
+impl fmt::Display for CloseFdsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed {}", self.phase)?;
+        if let Some(fd) = self.fd {
+            write!(f, " for fd {}", fd)?;
+        }
+        write!(f, ": {}", io::Error::from_raw_os_error(self.errno))
+    }
+}
+
+impl error::Error for CloseFdsError {}
+
+impl From<CloseFdsError> for io::Error {
+    fn from(err: CloseFdsError) -> io::Error {
+        io::Error::new(io::Error::from_raw_os_error(err.errno).kind(), err)
+    }
+}
+
+/// The encoded error for `errno` after `phase` failed. Safe to call after `fork()`.
+pub(crate) fn last_os_error(phase: Phase, fd: Option<RawFd>) -> io::Error {
+    let errno = io::Error::last_os_error()
+        .raw_os_error()
+        .unwrap_or(libc::EINVAL);
+    CloseFdsError::new(phase, errno, fd).into_raw()
+}
+
+/// The error number of `err`, whether it encodes a `CloseFdsError` or not.
+pub(crate) fn errno(err: &io::Error) -> Option<i32> {
+    match CloseFdsError::from_io_error(err) {
+        Some(err) => Some(err.errno()),
+        None => err.raw_os_error(),
+    }
+}
+
+/// Turn an encoded `CloseFdsError` into an `io::Error` that wraps it, in the parent process.
+pub(crate) fn decode(err: io::Error) -> io::Error {
+    match err
+        .raw_os_error()
+        .and_then(CloseFdsError::from_raw_os_error)
+    {
+        Some(err) => err.into(),
+        None => err,
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::{CStr, CString, OsStr, OsString},
+    io, iter,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    ptr,
+};
+
+use crate::Program;
+
+/// The program, arguments, and environment of a `Program`, prepared in the parent process as the
+/// `NULL` terminated arrays that `execve()` and `posix_spawn()` take.
+pub(crate) struct ExecArgs {
+    program: CString,
+    /// Owns the strings that `argv` points to.
+    _args: Vec<CString>,
+    /// Owns the strings that `envp` points to.
+    env: Vec<CString>,
+    argv: Box<[*const libc::c_char]>,
+    envp: Box<[*const libc::c_char]>,
+}
+
+// The pointers only point into the strings that the ExecArgs owns.
+unsafe impl Send for ExecArgs {}
+unsafe impl Sync for ExecArgs {}
+
+impl ExecArgs {
+    /// Prepare the arguments of `program`, with `extra_env` added to its environment.
+    pub(crate) fn from_program(
+        program: &Program,
+        extra_env: &[(&str, &str)],
+    ) -> io::Result<ExecArgs> {
+        let (changes, env_clear) = program.get_envs();
+        let mut vars: BTreeMap<OsString, OsString> = if env_clear {
+            BTreeMap::new()
+        } else {
+            env::vars_os().collect()
+        };
+        for (key, value) in changes {
+            match value {
+                Some(value) => vars.insert(key.to_owned(), value.to_owned()),
+                None => vars.remove(key),
+            };
+        }
+        for &(key, value) in extra_env {
+            vars.insert(key.into(), value.into());
+        }
+
+        ExecArgs::from_parts(program.get_program(), program.get_args(), vars)
+    }
+
+    fn from_parts<'a, I>(
+        program: &OsStr,
+        args: I,
+        vars: BTreeMap<OsString, OsString>,
+    ) -> io::Result<ExecArgs>
+    where
+        I: Iterator<Item = &'a OsStr>,
+    {
+        let program = cstring(program.as_bytes().to_vec())?;
+        let args = args
+            .map(|arg| cstring(arg.as_bytes().to_vec()))
+            .collect::<io::Result<Vec<_>>>()?;
+        let env = vars
+            .into_iter()
+            .map(|(key, value)| {
+                let mut var = key.into_vec();
+                var.push(b'=');
+                var.extend(value.into_vec());
+                cstring(var)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let argv = iter::once(program.as_ptr())
+            .chain(args.iter().map(|arg| arg.as_ptr()))
+            .chain(iter::once(ptr::null()))
+            .collect();
+        let envp = env
+            .iter()
+            .map(|var| var.as_ptr())
+            .chain(iter::once(ptr::null()))
+            .collect();
+
+        Ok(ExecArgs {
+            program,
+            _args: args,
+            env,
+            argv,
+            envp,
+        })
+    }
+
+    /// The paths that `execvp()` would try, using the `PATH` of the child process' environment.
+    pub(crate) fn paths(&self) -> io::Result<Vec<CString>> {
+        let program = self.program.as_bytes();
+        if program.contains(&b'/') {
+            return Ok(vec![self.program.clone()]);
+        }
+
+        let path = self.env_value("PATH").unwrap_or(b"/bin:/usr/bin");
+        path.split(|&b| b == b':')
+            .map(|dir| {
+                // An empty entry means the current directory.
+                let mut path = if dir.is_empty() {
+                    b".".to_vec()
+                } else {
+                    dir.to_vec()
+                };
+                path.push(b'/');
+                path.extend_from_slice(program);
+                cstring(path)
+            })
+            .collect()
+    }
+
+    pub(crate) fn program(&self) -> &CStr {
+        &self.program
+    }
+
+    pub(crate) fn argv(&self) -> *const *const libc::c_char {
+        self.argv.as_ptr()
+    }
+
+    pub(crate) fn envp(&self) -> *const *const libc::c_char {
+        self.envp.as_ptr()
+    }
+
+    /// The value of the environment variable `key`.
+    pub(crate) fn env_value(&self, key: &str) -> Option<&[u8]> {
+        self.env.iter().find_map(|var| {
+            let var = var.as_bytes();
+            if var.len() > key.len() && var.starts_with(key.as_bytes()) && var[key.len()] == b'=' {
+                Some(&var[key.len() + 1..])
+            } else {
+                None
+            }
+        })
+    }
+
+    /// A pointer to the value of the environment variable `key`, for patching it in place.
+    pub(crate) fn env_value_ptr(&self, key: &str) -> Option<*mut u8> {
+        self.env_value(key).map(|value| value.as_ptr() as *mut u8)
+    }
+}
+
+fn cstring(bytes: Vec<u8>) -> io::Result<CString> {
+    CString::new(bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
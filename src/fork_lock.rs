@@ -0,0 +1,227 @@
+use std::{
+    cell::{Cell, UnsafeCell},
+    fmt, io,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Once,
+    },
+};
+
+/// A process-wide lock that keeps file descriptors from leaking into child processes while
+/// they are being created without `FD_CLOEXEC`, like Go's `syscall.ForkLock`.
+///
+/// Some system calls and libraries can't create file descriptors with `FD_CLOEXEC` set, so there
+/// is a window between creating a file descriptor and setting the flag in which a child process
+/// spawned by another thread inherits it. Code that creates such file descriptors holds a read
+/// guard until the flag is set, and code that spawns child processes holds a write guard while
+/// it does:
+///
+/// ```no_run
+/// # use closefds::ForkLock;
+/// let mut fds = [0; 2];
+/// {
+///     let _guard = ForkLock::read();
+///     if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+///         return Err(std::io::Error::last_os_error());
+///     }
+///     for &fd in &fds {
+///         unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+///     }
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+///
+/// Once `read()` or `write()` has been called, every `fork()` in the process - including the
+/// ones of `Command` when it has a `pre_exec()` function - holds the write lock, using
+/// `pthread_atfork()`. `posix_spawn()` and `vfork()` don't run those handlers, so
+/// `CloseFdsBuilder::posix_spawn()` and `CloseFdsBuilder::vfork_spawn()` hold the write lock
+/// themselves. Other code that spawns processes without `fork()` - such as `Command` without a
+/// `pre_exec()` function, which uses `posix_spawn()` where it can - needs to hold a guard from
+/// `write()` while it does.
+///
+/// Guards only lock if the calling thread doesn't hold one already, so a thread that holds a
+/// read guard can still spawn child processes without deadlocking - but then without excluding
+/// other readers.
+///
+/// With glibc, a thread that waits for the write lock keeps new readers from locking, so that
+/// threads which keep creating file descriptors can't hold off spawning forever. Elsewhere, this
+/// depends on the `pthread_rwlock_t` of the C library. musl, for example, lets new readers in
+/// while a writer waits, so there, readers whose guards overlap without a gap can starve
+/// spawning threads.
+pub struct ForkLock {
+    _private: (),
+}
+
+struct RawLock(UnsafeCell<libc::pthread_rwlock_t>);
+
+// pthread_rwlock_t is meant to be shared between threads.
+unsafe impl Sync for RawLock {}
+
+static LOCK: RawLock = RawLock(UnsafeCell::new(libc::PTHREAD_RWLOCK_INITIALIZER));
+/// Initializes `LOCK` and registers the `pthread_atfork()` handlers before the first guard.
+static INIT: Once = Once::new();
+/// Whether the lock is held by the `pthread_atfork()` handlers of a `fork()` in progress.
+static LOCKED_FOR_FORK: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// The number of guards that the current thread holds.
+    static HELD_GUARDS: Cell<usize> = const { Cell::new(0) };
+}
+
+impl ForkLock {
+    /// Lock for creating file descriptors that don't have `FD_CLOEXEC` set yet. Any number of
+    /// threads can hold read guards at the same time.
+    pub fn read() -> ForkLockGuard {
+        ForkLock::lock(libc::pthread_rwlock_rdlock)
+    }
+
+    /// Lock for spawning child processes, excluding every thread that holds a read guard.
+    pub fn write() -> ForkLockGuard {
+        ForkLock::lock(libc::pthread_rwlock_wrlock)
+    }
+
+    fn lock(
+        lock: unsafe extern "C" fn(*mut libc::pthread_rwlock_t) -> libc::c_int,
+    ) -> ForkLockGuard {
+        INIT.call_once(|| {
+            let ret = unsafe { init_lock() };
+            if ret != 0 {
+                panic!(
+                    "initializing the ForkLock failed: {}",
+                    io::Error::from_raw_os_error(ret)
+                );
+            }
+            let ret = unsafe {
+                libc::pthread_atfork(
+                    Some(prepare_fork),
+                    Some(parent_after_fork),
+                    Some(child_after_fork),
+                )
+            };
+            if ret != 0 {
+                panic!(
+                    "pthread_atfork() failed: {}",
+                    io::Error::from_raw_os_error(ret)
+                );
+            }
+        });
+
+        let locked = HELD_GUARDS.with(|held| held.get()) == 0;
+        if locked {
+            let ret = unsafe { lock(LOCK.0.get()) };
+            if ret != 0 {
+                panic!(
+                    "locking the ForkLock failed: {}",
+                    io::Error::from_raw_os_error(ret)
+                );
+            }
+        }
+        HELD_GUARDS.with(|held| held.set(held.get() + 1));
+        ForkLockGuard {
+            locked,
+            not_send: PhantomData,
+        }
+    }
+}
+
+impl fmt::Debug for ForkLock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ForkLock").finish_non_exhaustive()
+    }
+}
+
+/// A read or write guard of the `ForkLock`, which unlocks it when dropped. It must be dropped
+/// by the thread that created it.
+#[must_use = "the ForkLock is unlocked when the guard is dropped"]
+pub struct ForkLockGuard {
+    /// Whether this guard locked the lock, rather than an outer guard of the same thread.
+    locked: bool,
+    not_send: PhantomData<*const ()>,
+}
+
+impl Drop for ForkLockGuard {
+    fn drop(&mut self) {
+        HELD_GUARDS.with(|held| held.set(held.get() - 1));
+        if self.locked {
+            unsafe {
+                libc::pthread_rwlock_unlock(LOCK.0.get());
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ForkLockGuard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ForkLockGuard").finish_non_exhaustive()
+    }
+}
+
+extern "C" fn prepare_fork() {
+    // A thread that holds a guard itself would deadlock.
+    if HELD_GUARDS.with(|held| held.get()) == 0
+        && unsafe { libc::pthread_rwlock_wrlock(LOCK.0.get()) } == 0
+    {
+        LOCKED_FOR_FORK.store(true, Ordering::SeqCst);
+    }
+}
+
+extern "C" fn parent_after_fork() {
+    if LOCKED_FOR_FORK.swap(false, Ordering::SeqCst) {
+        unsafe {
+            libc::pthread_rwlock_unlock(LOCK.0.get());
+        }
+    }
+}
+
+extern "C" fn child_after_fork() {
+    // The lock was taken by a thread of the parent process, which the child process doesn't
+    // have. No other thread exists yet, so it can be reset instead of unlocked.
+    if LOCKED_FOR_FORK.swap(false, Ordering::SeqCst) {
+        unsafe {
+            init_lock();
+        }
+    }
+}
+
+// glibc's default rwlock lets readers in while a writer waits. Neither the kind nor the
+// constant are in every libc version that this crate supports.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+const PTHREAD_RWLOCK_PREFER_WRITER_NONRECURSIVE_NP: libc::c_int = 2;
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+extern "C" {
+    fn pthread_rwlockattr_setkind_np(
+        attr: *mut libc::pthread_rwlockattr_t,
+        pref: libc::c_int,
+    ) -> libc::c_int;
+}
+
+/// Initialize `LOCK` as an unlocked lock that prefers writers where the C library supports that.
+/// `LOCK` must not be in use by any thread.
+///
+/// In glibc, `pthread_rwlock_init()` only stores to the lock, so this can be called in a child
+/// process after `fork()`.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+unsafe fn init_lock() -> libc::c_int {
+    let mut attr = std::mem::MaybeUninit::<libc::pthread_rwlockattr_t>::uninit();
+    let mut ret = libc::pthread_rwlockattr_init(attr.as_mut_ptr());
+    if ret != 0 {
+        return ret;
+    }
+    ret = pthread_rwlockattr_setkind_np(
+        attr.as_mut_ptr(),
+        PTHREAD_RWLOCK_PREFER_WRITER_NONRECURSIVE_NP,
+    );
+    if ret == 0 {
+        ret = libc::pthread_rwlock_init(LOCK.0.get(), attr.as_ptr());
+    }
+    libc::pthread_rwlockattr_destroy(attr.as_mut_ptr());
+    ret
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+unsafe fn init_lock() -> libc::c_int {
+    std::ptr::write(LOCK.0.get(), libc::PTHREAD_RWLOCK_INITIALIZER);
+    0
+}
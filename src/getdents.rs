@@ -0,0 +1,118 @@
+use std::{ffi::CStr, io, mem, os::unix::io::RawFd};
+
+use crate::{
+    error::{self, Phase},
+    pos_int_from_ascii,
+};
+
+// Large enough to read a few hundred entries per getdents64() call. The
+// buffer is made out of u64s so that it is suitably aligned for the
+// linux_dirent64 records the kernel writes into it.
+const BUF_WORDS: usize = 1024;
+
+// Offsets of the fields of struct linux_dirent64:
+//
+//     struct linux_dirent64 {
+//         ino64_t        d_ino;
+//         off64_t        d_off;
+//         unsigned short d_reclen;
+//         unsigned char  d_type;
+//         char           d_name[];
+//     };
+const D_RECLEN_OFFSET: usize = 16;
+const D_NAME_OFFSET: usize = 19;
+
+/// A directory that is read with the raw `getdents64()` system call.
+///
+/// Unlike `readdir()`, which reads into a buffer owned by libc's `DIR`
+/// structure, this reads into a buffer that was allocated when the directory
+/// was opened. Reading the directory only uses `open()`, `getdents64()`, and
+/// `close()` and so never calls into libc's allocator or takes any of libc's
+/// locks, which makes it safe to use between `fork()` and `exec()`.
+///
+/// The directory is opened again every time it is read. `/proc/self` is
+/// resolved when the directory is opened, so this makes sure that a child
+/// process reads its own fd table and not the one of the parent process
+/// that created the `GetdentsDir`.
+pub(crate) struct GetdentsDir {
+    path: &'static CStr,
+    buf: Box<[u64]>,
+}
+
+impl GetdentsDir {
+    pub(crate) fn open(dir_path: &'static CStr) -> io::Result<GetdentsDir> {
+        // Make sure that the directory is available so that we can report an
+        // error before fork() instead of after it.
+        let fd = open_dir(dir_path)?;
+        let _ = unsafe { libc::close(fd) };
+
+        Ok(GetdentsDir {
+            path: dir_path,
+            buf: vec![0; BUF_WORDS].into_boxed_slice(),
+        })
+    }
+
+    /// Call `f` with every file descriptor listed in the directory, except for
+    /// the file descriptor that is used to read the directory itself.
+    pub(crate) fn for_each_fd<F>(&mut self, f: F) -> io::Result<()>
+    where
+        F: FnMut(RawFd) -> io::Result<()>,
+    {
+        let fd = open_dir(self.path)?;
+        let result = self.read_entries(fd, f);
+        let _ = unsafe { libc::close(fd) };
+        result
+    }
+
+    fn read_entries<F>(&mut self, dir_fd: RawFd, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(RawFd) -> io::Result<()>,
+    {
+        let buf_len = self.buf.len() * mem::size_of::<u64>();
+        let buf = self.buf.as_mut_ptr() as *mut u8;
+
+        loop {
+            let read = unsafe { libc::syscall(libc::SYS_getdents64, dir_fd, buf, buf_len) };
+            if read == -1 {
+                return Err(error::last_os_error(Phase::ReadEntry, None));
+            }
+            if read == 0 {
+                break;
+            }
+
+            let mut offset = 0;
+            while offset < read as usize {
+                unsafe {
+                    let record = buf.add(offset);
+                    let reclen = (record.add(D_RECLEN_OFFSET) as *const u16).read();
+                    offset += reclen as usize;
+
+                    // Skip the "." and ".." entries.
+                    let name = record.add(D_NAME_OFFSET) as *const libc::c_char;
+                    if *name == '.' as libc::c_char {
+                        continue;
+                    }
+
+                    let fd = pos_int_from_ascii(name)?;
+                    if fd != dir_fd {
+                        f(fd)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn open_dir(dir_path: &CStr) -> io::Result<RawFd> {
+    let fd = unsafe {
+        libc::open(
+            dir_path.as_ptr(),
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )
+    };
+    if fd == -1 {
+        return Err(error::last_os_error(Phase::OpenDir, None));
+    }
+    Ok(fd)
+}
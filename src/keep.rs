@@ -0,0 +1,101 @@
+use std::{
+    ops::{Bound, RangeBounds},
+    os::unix::io::RawFd,
+};
+
+/// The file descriptors to keep open, stored as sorted, non-overlapping, non-adjacent, inclusive
+/// ranges so that large ranges don't take up any more space than single file descriptors.
+///
+/// Only the file descriptors that were named individually - rather than as part of a range -
+/// have `FD_CLOEXEC` cleared. A range just keeps its file descriptors from being processed, so
+/// file descriptors in it that already have `FD_CLOEXEC` set - such as the pipe that `Command`
+/// uses to report `exec()` errors - are still closed on `exec()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct KeepFds {
+    ranges: Vec<(RawFd, RawFd)>,
+    /// The file descriptors that were named individually, sorted and without duplicates.
+    fds: Vec<RawFd>,
+}
+
+impl KeepFds {
+    /// Keep the individual file descriptors in `fds` and every file descriptor in `ranges`.
+    pub(crate) fn new(mut fds: Vec<RawFd>, mut ranges: Vec<(RawFd, RawFd)>) -> KeepFds {
+        fds.retain(|&fd| fd >= 0);
+        fds.sort_unstable();
+        fds.dedup();
+
+        ranges.retain(|&(first, last)| first <= last && last >= 0);
+        for range in &mut ranges {
+            range.0 = range.0.max(0);
+        }
+        ranges.extend(fds.iter().map(|&fd| (fd, fd)));
+        ranges.sort_unstable();
+
+        let mut merged: Vec<(RawFd, RawFd)> = Vec::with_capacity(ranges.len());
+        for (first, last) in ranges {
+            match merged.last_mut() {
+                Some(prev) if first <= prev.1.saturating_add(1) => prev.1 = prev.1.max(last),
+                _ => merged.push((first, last)),
+            }
+        }
+
+        KeepFds {
+            ranges: merged,
+            fds,
+        }
+    }
+
+    pub(crate) fn from_fds(fds: &[RawFd]) -> KeepFds {
+        KeepFds::new(fds.to_vec(), Vec::new())
+    }
+
+    pub(crate) fn ranges(&self) -> &[(RawFd, RawFd)] {
+        &self.ranges
+    }
+
+    /// The file descriptors that were named individually, which have `FD_CLOEXEC` cleared.
+    pub(crate) fn fds(&self) -> &[RawFd] {
+        &self.fds
+    }
+
+    pub(crate) fn contains(&self, fd: RawFd) -> bool {
+        self.ranges
+            .binary_search_by(|&(first, last)| {
+                if last < fd {
+                    std::cmp::Ordering::Less
+                } else if first > fd {
+                    std::cmp::Ordering::Greater
+                } else {
+                    std::cmp::Ordering::Equal
+                }
+            })
+            .is_ok()
+    }
+
+    /// Whether `fd` was named individually, so that `FD_CLOEXEC` is cleared on it.
+    pub(crate) fn clears_cloexec(&self, fd: RawFd) -> bool {
+        self.fds.binary_search(&fd).is_ok()
+    }
+}
+
+/// Convert `range` into an inclusive range. The result is empty - the first file descriptor is
+/// greater than the last - if `range` is.
+pub(crate) fn to_inclusive<R: RangeBounds<RawFd>>(range: &R) -> (RawFd, RawFd) {
+    let first = match range.start_bound() {
+        Bound::Included(&first) => first,
+        Bound::Excluded(&first) => match first.checked_add(1) {
+            Some(first) => first,
+            None => return (1, 0),
+        },
+        Bound::Unbounded => 0,
+    };
+    let last = match range.end_bound() {
+        Bound::Included(&last) => last,
+        Bound::Excluded(&last) => match last.checked_sub(1) {
+            Some(last) => last,
+            None => return (1, 0),
+        },
+        Bound::Unbounded => RawFd::MAX,
+    };
+    (first, last)
+}
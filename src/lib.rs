@@ -16,55 +16,97 @@
 //!
 //! The function `close_fds_on_exec()` will create a closure that can be passed
 //! as a `pre_exec()` function when spawning a child process via the `Command` interface
-//! and will set the `FD_CLOEXEC` flag as appropriate on open file descriptors.
-
-use std::{ffi::CStr, io, os::unix::io::RawFd, ptr};
+//! and will set the `FD_CLOEXEC` flag as appropriate on open file descriptors. `CloseFds::builder()`
+//! creates the same closure from a more detailed configuration.
+//!
+//! `CommandExt` adds `close_fds()` to `Command`, which does the same without any `unsafe` code:
+//!
+//! ```no_run
+//! # use closefds::CommandExt;
+//! # use std::process::Command;
+//! # fn main() -> std::io::Result<()> {
+//! let child = Command::new("path/to/program").close_fds().spawn()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Code that calls `fork()` or `clone()` itself can prepare the same plan with `Prepared` and
+//! apply it in the child process. With the `capi` feature, this is also available to C and C++
+//! programs, which link against the `cdylib` or `staticlib` that the `closefds-capi` package
+//! builds and use `include/closefds.h`.
+//!
+//! `ForkLock` closes the window in which a file descriptor that couldn't be created with
+//! `FD_CLOEXEC` leaks into a child process that another thread spawns before the flag is set.
+//!
+//! With the `testing` feature, the `testing` module has helpers for test suites that check which
+//! file descriptors are left open or inherited by child processes.
 
-#[cfg(any(
-    target_os = "dragonfly",
-    target_os = "freebsd",
-    target_os = "netbsd",
-    target_os = "openbsd",
-    target_os = "macos",
-))]
-const FD_DIR_NAME: &'static [u8; 8] = b"/dev/fd\0";
+use std::{io, mem, os::unix::io::RawFd};
 
+mod audit;
+mod brute_force;
+mod builder;
+#[cfg(feature = "capi")]
+mod capi;
+#[cfg(target_os = "linux")]
+mod close_range;
+mod command_ext;
+mod error;
+mod exec_args;
+mod fork_lock;
 #[cfg(target_os = "linux")]
-const FD_DIR_NAME: &'static [u8; 14] = b"/proc/self/fd\0";
+mod getdents;
+mod keep;
+mod mode;
+mod posix_spawn;
+mod prepared;
+mod program;
+mod readdir;
+mod receiver;
+mod remap;
+mod sanitize;
+mod socket_activation;
+mod strategy;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "tokio")]
+mod tokio_process;
+#[cfg(target_os = "linux")]
+mod vfork;
 
-struct OpenDir {
-    dir: *mut libc::DIR,
-}
+pub use crate::{
+    audit::{audit, FdInfo, FdKind},
+    builder::CloseFdsBuilder,
+    command_ext::{CloseFdsCommand, CommandExt},
+    error::{CloseFdsError, Phase},
+    fork_lock::{ForkLock, ForkLockGuard},
+    mode::Mode,
+    posix_spawn::SpawnedChild,
+    prepared::Prepared,
+    program::Program,
+    receiver::{ReceivedFds, Receiver, MANIFEST_VAR},
+    sanitize::set_cloexec_on_all_except,
+    socket_activation::SocketActivation,
+    strategy::{probe, ProbeResult, Strategy},
+};
+use crate::{keep::KeepFds, remap::Remap, strategy::Backend};
 
-// My best understanding is that functions that work with a libc::DIR
-// do the appropriate locking to make it safe to work with from
-// multiple threads.
-unsafe impl Send for OpenDir {}
-unsafe impl Sync for OpenDir {}
-
-impl OpenDir {
-    fn open(dir_path: &CStr) -> io::Result<OpenDir> {
-        let dir = unsafe { libc::opendir(dir_path.as_ptr()) };
-        if dir == ptr::null_mut() {
-            return Err(io::Error::last_os_error());
-        }
-        Ok(OpenDir { dir })
-    }
-}
+// /proc/self/fd lists the file descriptors of the thread group leader, which
+// aren't the ones of the calling thread if it unshared its fd table with
+// unshare(CLONE_FILES). /proc/thread-self/fd always lists the file
+// descriptors of the calling thread, but only exists since Linux 3.17.
+#[cfg(target_os = "linux")]
+const FD_DIR_NAME: &[u8; 21] = b"/proc/thread-self/fd\0";
 
-impl Drop for OpenDir {
-    fn drop(&mut self) {
-        // This will likely call free() - which is why the closure that
-        // is created by close_fds_on_exec() should not be dropped by
-        // the child process after fork().
-        let _ = unsafe { libc::closedir(self.dir) };
-    }
-}
+#[cfg(target_os = "linux")]
+const FALLBACK_FD_DIR_NAME: &[u8; 14] = b"/proc/self/fd\0";
+
+const DEV_FD_DIR_NAME: &[u8; 8] = b"/dev/fd\0";
 
 fn set_cloexec(fd: RawFd, set: bool) -> io::Result<()> {
     let mut fd_flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
     if fd_flags == -1 {
-        return Err(io::Error::last_os_error());
+        return Err(error::last_os_error(Phase::Fcntl, Some(fd)));
     }
 
     let is_set = fd_flags & libc::FD_CLOEXEC != 0;
@@ -80,68 +122,195 @@ fn set_cloexec(fd: RawFd, set: bool) -> io::Result<()> {
     }
 
     if unsafe { libc::fcntl(fd, libc::F_SETFD, fd_flags) } == -1 {
-        return Err(io::Error::last_os_error());
+        return Err(error::last_os_error(Phase::Fcntl, Some(fd)));
     }
 
     Ok(())
 }
 
-unsafe fn pos_int_from_ascii(mut name: *const libc::c_char) -> io::Result<libc::c_int> {
+pub(crate) unsafe fn pos_int_from_ascii(mut name: *const libc::c_char) -> io::Result<libc::c_int> {
     let mut num = 0;
-    while *name >= '0' as i8 && *name <= '9' as i8 {
-        num = num * 10 + (*name - '0' as i8) as libc::c_int;
+    while *name >= '0' as libc::c_char && *name <= '9' as libc::c_char {
+        num = num * 10 + (*name - '0' as libc::c_char) as libc::c_int;
         name = name.offset(1);
     }
     // If the last byte isn't a NULL, it means we found a
     // non-digit.
     if *name != 0 {
-        errno::set_errno(errno::Errno(libc::ENOENT));
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "fd file name contained non-integer characters",
-        ));
+        return Err(CloseFdsError::new(Phase::ParseName, libc::EINVAL, None).into_raw());
     }
     Ok(num)
 }
 
-struct CloseFdsOnExec {
-    dir: OpenDir,
-    keep_fds: Vec<RawFd>,
+/// The state that is needed to process the open file descriptors after `fork()`.
+///
+/// A `CloseFds` is created with a `CloseFdsBuilder`, which is returned by `CloseFds::builder()`.
+///
+/// Dropping it in any process other than the one that created it - such as a child process
+/// after `fork()` - leaks its resources instead of freeing them, since freeing memory or closing
+/// a directory stream there may deadlock.
+pub struct CloseFds {
+    backend: Backend,
+    keep_fds: KeepFds,
+    remap: Remap,
+    mode: Mode,
+    /// The pid of the process that created the `CloseFds`.
+    pid: libc::pid_t,
 }
 
-impl CloseFdsOnExec {
-    pub fn new(mut keep_fds: Vec<RawFd>) -> io::Result<Self> {
-        let dir = OpenDir::open(CStr::from_bytes_with_nul(FD_DIR_NAME).expect("Invalid Path"))?;
-        keep_fds.sort_unstable();
-        Ok(CloseFdsOnExec { dir, keep_fds })
+impl CloseFds {
+    pub(crate) fn new(
+        keep_fds: KeepFds,
+        remap: Remap,
+        strategy: Strategy,
+        mode: Mode,
+    ) -> io::Result<Self> {
+        if strategy == Strategy::CloseRange && mode == Mode::Close {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the close_range strategy can't be used with Mode::Close",
+            ));
+        }
+
+        // Remapping a file descriptor onto the one of a directory would replace the directory.
+        let backend = Backend::new(strategy, remap.min_unused_fd())?;
+        Ok(CloseFds {
+            backend,
+            keep_fds,
+            remap,
+            mode,
+            pid: unsafe { libc::getpid() },
+        })
     }
 
-    pub fn before_exec(&mut self) -> io::Result<()> {
-        unsafe {
-            errno::set_errno(errno::Errno(0));
-            libc::rewinddir(self.dir.dir);
-            if errno::errno() != errno::Errno(0) {
-                return Err(io::Error::last_os_error());
-            }
+    /// Whether this is the process that created the `CloseFds`, rather than a child process.
+    pub(crate) fn is_in_creating_process(&self) -> bool {
+        unsafe { libc::getpid() == self.pid }
+    }
+
+    pub(crate) fn before_exec(&mut self) -> io::Result<()> {
+        // The targets are part of keep_fds, so this has to happen before the open file
+        // descriptors are processed.
+        self.remap.apply()?;
 
-            loop {
-                errno::set_errno(errno::Errno(0));
-                let dir_entry = libc::readdir(self.dir.dir);
-                if dir_entry == ptr::null_mut() {
-                    if errno::errno() != errno::Errno(0) {
-                        return Err(io::Error::last_os_error());
-                    } else {
-                        break;
+        let keep_fds = &self.keep_fds;
+        let mode = self.mode;
+
+        #[cfg(target_os = "linux")]
+        {
+            if let Backend::CloseRange(ref mut fallback) = self.backend {
+                let flags = match mode {
+                    // Each kept range means another close_range() call, so walking the open
+                    // file descriptors is cheaper if there are many of them.
+                    _ if fallback.is_some()
+                        && keep_fds.ranges().len() > MAX_CLOSE_RANGE_KEEP_RANGES =>
+                    {
+                        None
+                    }
+                    Mode::CloseOnExec => Some(libc::CLOSE_RANGE_CLOEXEC),
+                    Mode::CloseAll => Some(0),
+                    // close_range() can't skip the file descriptors that have FD_CLOEXEC set, so
+                    // Strategy::Auto goes straight to the fallback.
+                    Mode::Close => None,
+                };
+                if let Some(flags) = flags {
+                    match close_range_gaps(keep_fds, flags) {
+                        Ok(()) => return Ok(()),
+                        Err(ref err) if fallback.is_some() && close_range::is_unsupported(err) => {}
+                        Err(err) => return Err(err),
                     }
                 }
+                let dir = fallback
+                    .as_mut()
+                    .expect("close_range() skipped without a fallback");
+                return dir.for_each_fd(|fd| process_fd(keep_fds, mode, fd));
+            }
+        }
+
+        self.backend
+            .for_each_fd(|fd| process_fd(keep_fds, mode, fd))
+            .expect("Strategy doesn't walk file descriptors")
+    }
+}
 
-                let f = pos_int_from_ascii((*dir_entry).d_name.as_ptr())?;
-                let needs_cloexec = self.keep_fds.binary_search(&f).is_err();
-                set_cloexec(f, needs_cloexec)?;
+impl Drop for CloseFds {
+    fn drop(&mut self) {
+        if self.is_in_creating_process() {
+            return;
+        }
+        // The replacements don't own anything, so nothing is freed once they are dropped.
+        mem::forget(mem::replace(&mut self.backend, Backend::BruteForce));
+        mem::forget(mem::take(&mut self.keep_fds));
+        mem::forget(mem::take(&mut self.remap));
+    }
+}
+
+fn process_fd(keep_fds: &KeepFds, mode: Mode, fd: RawFd) -> io::Result<()> {
+    if keep_fds.clears_cloexec(fd) {
+        return set_cloexec(fd, false);
+    }
+    // Kept ranges leave the flag alone, so that file descriptors in them that are meant to be
+    // closed on exec() still are.
+    if keep_fds.contains(fd) {
+        return Ok(());
+    }
+
+    match mode {
+        Mode::CloseOnExec => set_cloexec(fd, true),
+        Mode::Close => {
+            let fd_flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+            if fd_flags == -1 {
+                return Err(error::last_os_error(Phase::Fcntl, Some(fd)));
             }
+            if fd_flags & libc::FD_CLOEXEC != 0 {
+                return Ok(());
+            }
+            close(fd)
+        }
+        Mode::CloseAll => close(fd),
+    }
+}
+
+fn close(fd: RawFd) -> io::Result<()> {
+    if unsafe { libc::close(fd) } == -1 {
+        let err = error::last_os_error(Phase::Close, Some(fd));
+        // The file descriptor is closed even if close() is interrupted.
+        if error::errno(&err) != Some(libc::EINTR) {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+const MAX_CLOSE_RANGE_KEEP_RANGES: usize = 1024;
+
+/// Call `close_range()` with `flags` on every file descriptor in the gaps between the kept file
+/// descriptors and then clear `FD_CLOEXEC` on the file descriptors that were kept individually.
+/// If the very first `close_range()` call fails, no file descriptors have been modified.
+#[cfg(target_os = "linux")]
+fn close_range_gaps(keep_fds: &KeepFds, flags: libc::c_uint) -> io::Result<()> {
+    let mut first: libc::c_uint = 0;
+    for &(keep_first, keep_last) in keep_fds.ranges() {
+        let keep_first = keep_first as libc::c_uint;
+        if keep_first > first {
+            close_range::close_range(first as RawFd, keep_first - 1, flags)?;
         }
-        Ok(())
+        first = keep_last as libc::c_uint + 1;
     }
+    if first <= RawFd::MAX as libc::c_uint {
+        close_range::close_range(first as RawFd, libc::c_uint::MAX, flags)?;
+    }
+
+    for &keep_fd in keep_fds.fds() {
+        match set_cloexec(keep_fd, false) {
+            Ok(()) => {}
+            // Kept file descriptors don't have to be open.
+            Err(ref err) if error::errno(err) == Some(libc::EBADF) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
 }
 
 /// Create a closure that will set the `FD_CLOEXEC` flag on all open file descriptors when called.
@@ -160,24 +329,51 @@ impl CloseFdsOnExec {
 ///
 /// # Current Implementation
 ///
-/// The current implementation opens either the `/proc/self/fd/` directory (Linux) or `/dev/fd/`
-/// directory (BSDs) in the parent process with `opendir()`. `readdir()` is used in the child
-/// process to iterate over the entries in that directory and set the `FD_CLOEXEC` flag as
-/// appropriate.
+/// On Linux 5.11 and later, the child process uses `close_range()` with the
+/// `CLOSE_RANGE_CLOEXEC` flag to set the `FD_CLOEXEC` flag on every file descriptor in the
+/// gaps between the file descriptors in `keep_fds`, without having to look at each open file
+/// descriptor individually. If `close_range()` is not available - either because the kernel is
+/// too old or because a seccomp filter rejects it - the implementation falls back to the
+/// directory walk described below.
+///
+/// As the fallback on Linux, the implementation allocates a fixed size buffer for directory
+/// entries in the parent process. The child process opens the `/proc/thread-self/fd/` directory
+/// (or `/proc/self/fd/` on kernels older than 3.17) with `open()` - so that it lists the
+/// child's own file descriptors rather than those of the parent, which may have changed since
+/// `fork()` - reads it into that buffer with the raw `getdents64()` system call, and parses the
+/// entries itself. Nothing after `fork()` touches libc's allocator or takes any of libc's locks.
+///
+/// On other operating systems, the implementation opens the `/dev/fd/` directory in the parent
+/// process with `opendir()`. The child process replaces the directory's file descriptor with a
+/// freshly opened one - so that it doesn't share a directory offset with the parent or with
+/// other children spawned at the same time - and then uses `readdir()` to iterate over the
+/// entries in that directory and set the `FD_CLOEXEC` flag as appropriate.
 ///
 /// Notes:
 ///
+/// * The closure never allocates memory, not even to report errors - see `CloseFdsError`. It
+///   also doesn't take any locks that another thread of the parent process could have held at
+///   the time of `fork()`, so it is safe to use in processes whose allocator uses locks, like
+///   jemalloc. All of this also applies to `CloseFds::builder()` and the ways it spawns child
+///   processes.
+///
 /// * `readdir()` is not async-signal-safe according to any standard. However, the process
-/// spawning code in both Python and Java work similarly, so `readdir()` seems
-/// to be safe to call in practice after `fork()`.
+///   spawning code in both Python and Java work similarly, so `readdir()` seems
+///   to be safe to call in practice after `fork()`. The only lock it takes is the one of the
+///   directory stream, which no thread of the parent process ever reads.
+///
+/// * `/proc/thread-self/fd/`, `/proc/self/fd/`, or `/dev/fd/` directories _must_ be available,
+///   even if `close_range()` ends up being used.
 ///
-/// * `/proc/self/fd/` or `/dev/fd/` directories _must_ be available.
+/// * The returned closure needs to be dropped in the parent process in order to free its
+///   buffer or close the opened directory. Dropping it in the child process - for example in
+///   code that calls `fork()` itself - is safe: it remembers the pid of the process that created
+///   it and leaks its resources in any other process instead of calling `free()`, which may
+///   deadlock there. They are freed when `exec()` occurs or the child process exits.
 ///
-/// * The returned closure needs to be dropped in the parent process in order to close
-/// the opened directory. However, it must not be dropped in the child process as doing
-/// so will call `free()` which may deadlock - all resources will instead be freed when
-/// `exec()` occurs. (The standard library `CommandExt` interface does not drop closures
-/// before `exec()`).
+/// This describes `Strategy::Auto`. `close_fds_on_exec_with_strategy()` can be used to pick a
+/// specific strategy instead. `CloseFds::builder()` can also be used to move file descriptors to
+/// other numbers in the child process before they are processed.
 ///
 /// # Future Implementations
 ///
@@ -207,17 +403,91 @@ impl CloseFdsOnExec {
 /// # }
 /// ```
 pub fn close_fds_on_exec(keep_fds: Vec<RawFd>) -> io::Result<impl FnMut() -> io::Result<()>> {
-    let mut close_fds_on_exec = CloseFdsOnExec::new(keep_fds)?;
+    close_fds_on_exec_with_strategy(keep_fds, Strategy::Auto)
+}
+
+/// Create a closure like `close_fds_on_exec()` does, but use the given `Strategy` to find the
+/// open file descriptors instead of picking one automatically.
+///
+/// Creating the closure fails if `strategy` isn't available on the current operating system.
+/// However, whether a strategy works may also depend on the kernel version or on the
+/// environment - for example, `Strategy::CloseRange` fails when called if the kernel doesn't
+/// support it. `probe()` can be used to check ahead of time.
+///
+/// # Example
+///
+/// ```no_run
+/// # use closefds::{close_fds_on_exec_with_strategy, Strategy};
+/// # use std::process::Command;
+/// # use std::os::unix::process::CommandExt;
+/// # fn main() -> std::io::Result<()> {
+/// # unsafe {
+/// Command::new("path/to/program")
+///     .pre_exec(close_fds_on_exec_with_strategy(vec![0, 1, 2], Strategy::Getdents)?)
+///     .spawn()
+///     .expect("Spawn Failed");
+/// # }
+/// # Ok(())
+/// # }
+/// ```
+pub fn close_fds_on_exec_with_strategy(
+    keep_fds: Vec<RawFd>,
+    strategy: Strategy,
+) -> io::Result<impl FnMut() -> io::Result<()>> {
+    let mut close_fds_on_exec = CloseFds::new(
+        KeepFds::from_fds(&keep_fds),
+        Remap::default(),
+        strategy,
+        Mode::CloseOnExec,
+    )?;
 
     let func = move || close_fds_on_exec.before_exec();
 
     Ok(func)
 }
 
+/// Create a closure that will set the `FD_CLOEXEC` flag on every open file descriptor at or
+/// above `lowfd` when called, similar to `closefrom()` on the BSDs.
+///
+/// The file descriptors below `lowfd` are left alone - unlike on the file descriptors passed to
+/// `close_fds_on_exec()`, the `FD_CLOEXEC` flag isn't cleared on them, so the ones that already
+/// have it set are still closed on `exec()`. On Linux, the whole range at
+/// or above `lowfd` is processed with a single `close_range()` call if possible. Otherwise,
+/// this works just like `close_fds_on_exec()`.
+///
+/// # Example
+///
+/// The following example will spawn a child process that inherits file descriptors 0 through 9.
+///
+/// ```no_run
+/// # use closefds::close_fds_from;
+/// # use std::process::Command;
+/// # use std::os::unix::process::CommandExt;
+/// # fn main() -> std::io::Result<()> {
+/// # unsafe {
+/// Command::new("path/to/program")
+///     .pre_exec(close_fds_from(10)?)
+///     .spawn()
+///     .expect("Spawn Failed");
+/// # }
+/// # Ok(())
+/// # }
+/// ```
+pub fn close_fds_from(lowfd: RawFd) -> io::Result<impl FnMut() -> io::Result<()>> {
+    CloseFds::builder().keep_range(..lowfd).build()
+}
+
 #[allow(dead_code)]
 fn assert_traits() {
     fn check_traits<T: Send + Sync + 'static>(_: T) {}
 
     check_traits(close_fds_on_exec(vec![]));
-    check_traits(CloseFdsOnExec::new(vec![]));
+    check_traits(CloseFds::builder().build());
+    check_traits(close_fds_from(3));
+    check_traits(CloseFds::new(
+        KeepFds::default(),
+        Remap::default(),
+        Strategy::Auto,
+        Mode::CloseOnExec,
+    ));
 }
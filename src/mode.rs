@@ -0,0 +1,30 @@
+/// What to do with the file descriptors that aren't kept open.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Mode {
+    /// Set the `FD_CLOEXEC` flag so that `exec()` closes the file descriptors.
+    #[default]
+    CloseOnExec,
+
+    /// Close the file descriptors right away, which frees their kernel resources before
+    /// `exec()` - or even without an `exec()`.
+    ///
+    /// File descriptors that already have the `FD_CLOEXEC` flag set are left for `exec()` to
+    /// close. This is what makes this mode safe to use with `std::process::Command`, which
+    /// reports `exec()` errors to the parent process over a pipe that has the `FD_CLOEXEC` flag
+    /// set. It also means that the file descriptor used to read the fd directory is never closed
+    /// in the middle of reading it.
+    ///
+    /// Since `close_range()` can't tell those file descriptors apart, this mode always walks the
+    /// open file descriptors, and `Strategy::CloseRange` can't be used with it.
+    Close,
+
+    /// Close all of the file descriptors right away, even the ones that have the `FD_CLOEXEC`
+    /// flag set. On Linux 5.9 and later, this uses `close_range()` if the `Strategy` allows it.
+    ///
+    /// This mode is meant for child processes that don't call `exec()` after `fork()`. It must
+    /// not be used with `std::process::Command`, as it closes the pipe that `Command` uses to
+    /// report `exec()` errors and those errors would then be lost. The file descriptor used to
+    /// read the fd directory is still never closed in the middle of reading it.
+    CloseAll,
+}
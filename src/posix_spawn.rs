@@ -0,0 +1,408 @@
+use std::{
+    ffi::{CStr, CString, OsStr},
+    fmt, fs, io, mem,
+    os::unix::{ffi::OsStrExt, io::RawFd, process::ExitStatusExt},
+    process::{self, ExitStatus},
+};
+
+use crate::{exec_args::ExecArgs, keep::KeepFds, ForkLock, Program};
+
+type AddCloseFromFn =
+    unsafe extern "C" fn(*mut libc::posix_spawn_file_actions_t, libc::c_int) -> libc::c_int;
+type AddChdirFn =
+    unsafe extern "C" fn(*mut libc::posix_spawn_file_actions_t, *const libc::c_char) -> libc::c_int;
+
+/// A child process that was spawned by `CloseFdsBuilder::posix_spawn()` or
+/// `CloseFdsBuilder::vfork_spawn()`.
+///
+/// This wraps either a process that this crate spawned itself or, if `posix_spawn()` couldn't be
+/// used, a `std::process::Child`. Only the methods of `Child` that
+/// make sense for both are available - the standard streams of the child process are whatever
+/// was remapped to 0, 1, and 2 or inherited from the parent process.
+pub struct SpawnedChild {
+    inner: Inner,
+}
+
+enum Inner {
+    /// A process that this crate spawned itself, either with `posix_spawn()` or with
+    /// `CloseFdsBuilder::vfork_spawn()`.
+    Pid {
+        pid: libc::pid_t,
+        status: Option<ExitStatus>,
+        posix_spawn: bool,
+    },
+    Std(process::Child),
+}
+
+impl fmt::Debug for SpawnedChild {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SpawnedChild")
+            .field("id", &self.id())
+            .field("posix_spawn", &self.is_posix_spawn())
+            .finish()
+    }
+}
+
+impl From<process::Child> for SpawnedChild {
+    fn from(child: process::Child) -> Self {
+        SpawnedChild {
+            inner: Inner::Std(child),
+        }
+    }
+}
+
+impl SpawnedChild {
+    pub(crate) fn from_pid(pid: libc::pid_t, posix_spawn: bool) -> Self {
+        SpawnedChild {
+            inner: Inner::Pid {
+                pid,
+                status: None,
+                posix_spawn,
+            },
+        }
+    }
+
+    /// The pid of the child process.
+    pub fn id(&self) -> u32 {
+        match self.inner {
+            Inner::Pid { pid, .. } => pid as u32,
+            Inner::Std(ref child) => child.id(),
+        }
+    }
+
+    /// Whether the child process was spawned with `posix_spawn()`, rather than with `Command`
+    /// and a `pre_exec()` function.
+    pub fn is_posix_spawn(&self) -> bool {
+        match self.inner {
+            Inner::Pid { posix_spawn, .. } => posix_spawn,
+            Inner::Std(_) => false,
+        }
+    }
+
+    /// Send `SIGKILL` to the child process, like `Child::kill()` does. Nothing is sent if the
+    /// child process has already been waited for.
+    pub fn kill(&mut self) -> io::Result<()> {
+        match self.inner {
+            Inner::Pid {
+                status: Some(_), ..
+            } => Ok(()),
+            Inner::Pid {
+                pid, status: None, ..
+            } => {
+                if unsafe { libc::kill(pid, libc::SIGKILL) } == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            }
+            Inner::Std(ref mut child) => child.kill(),
+        }
+    }
+
+    /// Wait for the child process to exit, like `Child::wait()` does.
+    pub fn wait(&mut self) -> io::Result<ExitStatus> {
+        match self.inner {
+            Inner::Pid {
+                status: Some(status),
+                ..
+            } => Ok(status),
+            Inner::Pid {
+                pid,
+                ref mut status,
+                ..
+            } => {
+                let exit_status = waitpid(pid, 0)?.expect("waitpid() returned without a status");
+                *status = Some(exit_status);
+                Ok(exit_status)
+            }
+            Inner::Std(ref mut child) => child.wait(),
+        }
+    }
+
+    /// Check whether the child process has exited without blocking, like `Child::try_wait()`
+    /// does.
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        match self.inner {
+            Inner::Pid {
+                status: Some(status),
+                ..
+            } => Ok(Some(status)),
+            Inner::Pid {
+                pid,
+                ref mut status,
+                ..
+            } => {
+                *status = waitpid(pid, libc::WNOHANG)?;
+                Ok(*status)
+            }
+            Inner::Std(ref mut child) => child.try_wait(),
+        }
+    }
+}
+
+pub(crate) fn waitpid(pid: libc::pid_t, options: libc::c_int) -> io::Result<Option<ExitStatus>> {
+    let mut status = 0;
+    loop {
+        match unsafe { libc::waitpid(pid, &mut status, options) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() != Some(libc::EINTR) {
+                    return Err(err);
+                }
+            }
+            0 => return Ok(None),
+            _ => return Ok(Some(ExitStatus::from_raw(status))),
+        }
+    }
+}
+
+/// Spawn `program` with `posix_spawn()`. Returns `None` if the plan can't be carried out with
+/// `posix_spawn()` on the current system, in which case nothing has been spawned.
+///
+/// `posix_spawn()` can't look at the open file descriptors in the child process, so everything
+/// from the first file descriptor that isn't kept up is closed with
+/// `posix_spawn_file_actions_addclosefrom_np()`. That only works if the kept file descriptors
+/// are a single range that starts at 0.
+pub(crate) fn spawn(
+    program: &Program,
+    keep_fds: &KeepFds,
+    remap: &[(RawFd, RawFd)],
+) -> Option<io::Result<SpawnedChild>> {
+    let last_kept = match *keep_fds.ranges() {
+        [] => -1,
+        [(0, last)] => last,
+        _ => return None,
+    };
+
+    let add_close_from: AddCloseFromFn =
+        unsafe { lookup(b"posix_spawn_file_actions_addclosefrom_np\0")? };
+    let cwd = match program.get_current_dir() {
+        Some(cwd) => {
+            let add_chdir: AddChdirFn =
+                unsafe { lookup(b"posix_spawn_file_actions_addchdir_np\0")? };
+            let cwd = match CString::new(cwd.as_os_str().as_bytes()) {
+                Ok(cwd) => cwd,
+                Err(err) => return Some(Err(io::Error::new(io::ErrorKind::InvalidInput, err))),
+            };
+            Some((add_chdir, cwd))
+        }
+        None => None,
+    };
+
+    // Sources are first duplicated to temporary file descriptors above everything else that is
+    // involved, like Remap does.
+    let max_fd = remap
+        .iter()
+        .map(|&(from, to)| from.max(to))
+        .chain(Some(last_kept))
+        .max()
+        .unwrap_or(-1);
+    let min_temp = max_fd.checked_add(1)?;
+    min_temp.checked_add(remap.len() as RawFd)?;
+
+    let args = match ExecArgs::from_program(program, &[]) {
+        Ok(args) => args,
+        Err(err) => return Some(Err(err)),
+    };
+    let path = match find_program(&args, cwd.is_some())? {
+        Ok(path) => path,
+        Err(err) => return Some(Err(err)),
+    };
+
+    Some(spawn_with(
+        &args,
+        &path,
+        keep_fds,
+        last_kept,
+        remap,
+        min_temp,
+        add_close_from,
+        cwd,
+    ))
+}
+
+/// Find the file that `execvp()` would run in the child process, so that `posix_spawn()` can be
+/// used instead of `posix_spawnp()`, which searches the `PATH` of the parent process. Returns
+/// `None` if the child process would search relative directories of its `PATH` in another
+/// working directory.
+fn find_program(args: &ExecArgs, has_cwd: bool) -> Option<io::Result<CString>> {
+    if args.program().to_bytes().contains(&b'/') {
+        // posix_spawn() reports any errors for an explicit path.
+        return Some(Ok(args.program().to_owned()));
+    }
+    let paths = match args.paths() {
+        Ok(paths) => paths,
+        Err(err) => return Some(Err(err)),
+    };
+    if has_cwd && paths.iter().any(|path| !path.to_bytes().starts_with(b"/")) {
+        return None;
+    }
+
+    // Like execvp(), report EACCES if any file was found but can't be executed.
+    let mut error = libc::ENOENT;
+    for path in paths {
+        let is_file = fs::metadata(OsStr::from_bytes(path.to_bytes()))
+            .map(|metadata| metadata.is_file())
+            .unwrap_or(false);
+        if !is_file {
+            continue;
+        }
+        if unsafe { libc::faccessat(libc::AT_FDCWD, path.as_ptr(), libc::X_OK, libc::AT_EACCESS) }
+            == 0
+        {
+            return Some(Ok(path));
+        }
+        error = libc::EACCES;
+    }
+    Some(Err(io::Error::from_raw_os_error(error)))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn spawn_with(
+    args: &ExecArgs,
+    path: &CStr,
+    keep_fds: &KeepFds,
+    last_kept: RawFd,
+    remap: &[(RawFd, RawFd)],
+    min_temp: RawFd,
+    add_close_from: AddCloseFromFn,
+    cwd: Option<(AddChdirFn, CString)>,
+) -> io::Result<SpawnedChild> {
+    let mut actions = FileActions::new()?;
+    for (i, &(from, _)) in remap.iter().enumerate() {
+        check(unsafe {
+            libc::posix_spawn_file_actions_adddup2(
+                actions.as_mut_ptr(),
+                from,
+                min_temp + i as RawFd,
+            )
+        })?;
+    }
+    for (i, &(_, to)) in remap.iter().enumerate() {
+        check(unsafe {
+            libc::posix_spawn_file_actions_adddup2(actions.as_mut_ptr(), min_temp + i as RawFd, to)
+        })?;
+    }
+
+    // dup2() onto the same file descriptor clears FD_CLOEXEC in posix_spawn(). It fails if the
+    // file descriptor isn't open, so only the individually kept file descriptors that are open
+    // in the parent process with FD_CLOEXEC set are included. The ones in kept ranges keep
+    // their flag, like they do with the other ways of spawning.
+    for &fd in keep_fds.fds() {
+        if remap.iter().any(|&(_, to)| to == fd) {
+            continue;
+        }
+        let fd_flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if fd_flags != -1 && fd_flags & libc::FD_CLOEXEC != 0 {
+            check(unsafe { libc::posix_spawn_file_actions_adddup2(actions.as_mut_ptr(), fd, fd) })?;
+        }
+    }
+
+    if let Some(low_fd) = last_kept.checked_add(1) {
+        check(unsafe { add_close_from(actions.as_mut_ptr(), low_fd) })?;
+    }
+    if let Some((add_chdir, ref cwd)) = cwd {
+        check(unsafe { add_chdir(actions.as_mut_ptr(), cwd.as_ptr()) })?;
+    }
+
+    // Like Command, reset the signal mask and SIGPIPE, which the Rust runtime ignores.
+    let mut attr = SpawnAttr::new()?;
+    unsafe {
+        let mut set = mem::MaybeUninit::uninit();
+        libc::sigemptyset(set.as_mut_ptr());
+        check(libc::posix_spawnattr_setsigmask(
+            attr.as_mut_ptr(),
+            set.as_ptr(),
+        ))?;
+        libc::sigaddset(set.as_mut_ptr(), libc::SIGPIPE);
+        check(libc::posix_spawnattr_setsigdefault(
+            attr.as_mut_ptr(),
+            set.as_ptr(),
+        ))?;
+        check(libc::posix_spawnattr_setflags(
+            attr.as_mut_ptr(),
+            (libc::POSIX_SPAWN_SETSIGMASK | libc::POSIX_SPAWN_SETSIGDEF) as _,
+        ))?;
+    }
+
+    // posix_spawn() doesn't run the pthread_atfork() handlers that take the ForkLock.
+    let fork_guard = ForkLock::write();
+    let mut pid = 0;
+    let result = check(unsafe {
+        libc::posix_spawn(
+            &mut pid,
+            path.as_ptr(),
+            actions.as_mut_ptr(),
+            attr.as_mut_ptr(),
+            args.argv() as *const *mut libc::c_char,
+            args.envp() as *const *mut libc::c_char,
+        )
+    });
+    drop(fork_guard);
+    result?;
+
+    Ok(SpawnedChild::from_pid(pid, true))
+}
+
+/// Look up an optional libc function at runtime, so that the crate still links against libcs
+/// that don't have it.
+unsafe fn lookup<F: Copy>(name: &[u8]) -> Option<F> {
+    let ptr = libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr() as *const libc::c_char);
+    if ptr.is_null() {
+        None
+    } else {
+        Some(mem::transmute_copy(&ptr))
+    }
+}
+
+/// posix_spawn() functions return the error number instead of setting `errno`.
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(ret))
+    }
+}
+
+struct FileActions(mem::MaybeUninit<libc::posix_spawn_file_actions_t>);
+
+impl FileActions {
+    fn new() -> io::Result<FileActions> {
+        let mut actions = FileActions(mem::MaybeUninit::uninit());
+        check(unsafe { libc::posix_spawn_file_actions_init(actions.0.as_mut_ptr()) })?;
+        Ok(actions)
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut libc::posix_spawn_file_actions_t {
+        self.0.as_mut_ptr()
+    }
+}
+
+impl Drop for FileActions {
+    fn drop(&mut self) {
+        unsafe {
+            libc::posix_spawn_file_actions_destroy(self.0.as_mut_ptr());
+        }
+    }
+}
+
+struct SpawnAttr(mem::MaybeUninit<libc::posix_spawnattr_t>);
+
+impl SpawnAttr {
+    fn new() -> io::Result<SpawnAttr> {
+        let mut attr = SpawnAttr(mem::MaybeUninit::uninit());
+        check(unsafe { libc::posix_spawnattr_init(attr.0.as_mut_ptr()) })?;
+        Ok(attr)
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut libc::posix_spawnattr_t {
+        self.0.as_mut_ptr()
+    }
+}
+
+impl Drop for SpawnAttr {
+    fn drop(&mut self) {
+        unsafe {
+            libc::posix_spawnattr_destroy(self.0.as_mut_ptr());
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::{cell::UnsafeCell, fmt, io, marker::PhantomData, os::unix::io::BorrowedFd};
+
+use errno::Errno;
+
+use crate::{CloseFds, CloseFdsBuilder};
+
+/// A plan that has been prepared in the parent process, for code that calls `fork()` or
+/// `clone()` itself rather than using `Command`.
+///
+/// `new()` does everything that may allocate memory or fail because of the state of the parent
+/// process, and `apply_in_child()` then processes the file descriptors in the child process.
+/// Unlike with `Command`, `Mode::CloseAll` can be used here.
+///
+/// # Example
+///
+/// ```no_run
+/// # use closefds::{CloseFds, Prepared};
+/// # fn main() -> std::io::Result<()> {
+/// let prepared = Prepared::new(CloseFds::builder().keep_stdio(true))?;
+/// match unsafe { libc::fork() } {
+///     -1 => return Err(std::io::Error::last_os_error()),
+///     0 => unsafe {
+///         if prepared.apply_in_child().is_err() {
+///             libc::_exit(127);
+///         }
+///         // exec() or run code that doesn't need the other file descriptors.
+///         libc::_exit(0);
+///     },
+///     pid => {
+///         // Wait for the child process.
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Prepared<'fd> {
+    close_fds: UnsafeCell<CloseFds>,
+    borrowed_fds: PhantomData<BorrowedFd<'fd>>,
+}
+
+// Only the child process ever mutates the CloseFds, through apply_in_child(), whose contract
+// rules out concurrent use.
+unsafe impl Send for Prepared<'_> {}
+
+impl<'fd> Prepared<'fd> {
+    /// Prepare the plan of `builder`. File descriptors that the builder borrows stay borrowed
+    /// for as long as the `Prepared` exists.
+    pub fn new(builder: CloseFdsBuilder<'fd>) -> io::Result<Prepared<'fd>> {
+        Ok(Prepared {
+            close_fds: UnsafeCell::new(builder.build_close_fds()?),
+            borrowed_fds: PhantomData,
+        })
+    }
+
+    /// Process the file descriptors of the calling process according to the plan.
+    ///
+    /// This doesn't allocate memory or take any locks, so it can be called right after `fork()`
+    /// or in the callback of `clone()`. On failure, the error number is the raw OS error code of
+    /// a `CloseFdsError`, which `CloseFdsError::from_raw_os_error()` decodes.
+    ///
+    /// # Safety
+    ///
+    /// This must only be called in a child process right after `fork()` or in the callback of
+    /// `clone()`, and never by two processes that share their memory - such as children created
+    /// with `CLONE_VM` - at the same time. Calling it in the parent process would process the
+    /// parent's file descriptors while other threads may be using them.
+    pub unsafe fn apply_in_child(&self) -> Result<(), Errno> {
+        (*self.close_fds.get())
+            .before_exec()
+            .map_err(|err| Errno(err.raw_os_error().unwrap_or(libc::EINVAL)))
+    }
+
+    #[cfg(feature = "capi")]
+    pub(crate) fn is_in_creating_process(&self) -> bool {
+        unsafe { (*self.close_fds.get()).is_in_creating_process() }
+    }
+}
+
+impl fmt::Debug for Prepared<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Prepared").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// A program to run in a child process, along with its arguments, environment, and working
+/// directory, for `CloseFdsBuilder::posix_spawn()` and `CloseFdsBuilder::vfork_spawn()`.
+///
+/// These don't use `Command`, since they can't see all of its settings. A `Program` only has
+/// settings that every way of spawning the child process honors. In particular, it has no
+/// settings for the standard streams: the child process gets whatever the `CloseFdsBuilder`
+/// leaves at the file descriptors 0, 1, and 2 - kept with `keep_stdio()` or moved there with
+/// `remap()`.
+///
+/// # Example
+///
+/// ```no_run
+/// # use closefds::{CloseFds, Program};
+/// # use std::fs::File;
+/// # fn main() -> std::io::Result<()> {
+/// let log = File::create("log.txt")?;
+/// let program = Program::new("path/to/program")
+///     .arg("--verbose")
+///     .env_clear()
+///     .env("PATH", "/bin:/usr/bin");
+/// let mut child = CloseFds::builder()
+///     .keep_stdio(true)
+///     .remap_fd(&log, 1)
+///     .posix_spawn(&program)?;
+/// child.wait()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Program {
+    program: OsString,
+    args: Vec<OsString>,
+    /// Variables to set, or to remove if `None`.
+    env: BTreeMap<OsString, Option<OsString>>,
+    env_clear: bool,
+    current_dir: Option<PathBuf>,
+}
+
+impl Program {
+    /// Run `program`, which is looked up in the `PATH` of the child process' environment if it
+    /// doesn't contain a `/`, like `Command::new()` does.
+    pub fn new<S: AsRef<OsStr>>(program: S) -> Self {
+        Program {
+            program: program.as_ref().to_owned(),
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            env_clear: false,
+            current_dir: None,
+        }
+    }
+
+    /// Add an argument.
+    pub fn arg<S: AsRef<OsStr>>(mut self, arg: S) -> Self {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    /// Add several arguments.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.args
+            .extend(args.into_iter().map(|arg| arg.as_ref().to_owned()));
+        self
+    }
+
+    /// Set an environment variable in the child process.
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(mut self, key: K, value: V) -> Self {
+        self.env
+            .insert(key.as_ref().to_owned(), Some(value.as_ref().to_owned()));
+        self
+    }
+
+    /// Set several environment variables in the child process.
+    pub fn envs<I, K, V>(self, vars: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        vars.into_iter()
+            .fold(self, |program, (key, value)| program.env(key, value))
+    }
+
+    /// Remove an environment variable from the child process' environment.
+    pub fn env_remove<K: AsRef<OsStr>>(mut self, key: K) -> Self {
+        self.env.insert(key.as_ref().to_owned(), None);
+        self
+    }
+
+    /// Don't pass on the environment of the parent process. Only the variables that are set
+    /// after this are passed to the child process.
+    pub fn env_clear(mut self) -> Self {
+        self.env.clear();
+        self.env_clear = true;
+        self
+    }
+
+    /// Run the program in `dir`.
+    pub fn current_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.current_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    pub(crate) fn get_program(&self) -> &OsStr {
+        &self.program
+    }
+
+    pub(crate) fn get_args(&self) -> impl Iterator<Item = &OsStr> {
+        self.args.iter().map(|arg| arg.as_os_str())
+    }
+
+    /// The variables to set or remove, and whether the environment of the parent process is
+    /// cleared first.
+    pub(crate) fn get_envs(&self) -> (&BTreeMap<OsString, Option<OsString>>, bool) {
+        (&self.env, self.env_clear)
+    }
+
+    pub(crate) fn get_current_dir(&self) -> Option<&Path> {
+        self.current_dir.as_deref()
+    }
+
+    /// A `Command` with the same settings, with the standard streams inherited.
+    pub(crate) fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(&self.args);
+        if self.env_clear {
+            cmd.env_clear();
+        }
+        for (key, value) in &self.env {
+            match value {
+                Some(value) => cmd.env(key, value),
+                None => cmd.env_remove(key),
+            };
+        }
+        if let Some(ref dir) = self.current_dir {
+            cmd.current_dir(dir);
+        }
+        cmd
+    }
+}
@@ -0,0 +1,141 @@
+use std::{ffi::CStr, io, os::unix::io::RawFd};
+
+use crate::{
+    error::{self, Phase},
+    pos_int_from_ascii, set_cloexec,
+};
+
+pub(crate) struct OpenDir {
+    path: &'static CStr,
+    dir: *mut libc::DIR,
+}
+
+// My best understanding is that functions that work with a libc::DIR
+// do the appropriate locking to make it safe to work with from
+// multiple threads.
+unsafe impl Send for OpenDir {}
+unsafe impl Sync for OpenDir {}
+
+impl OpenDir {
+    /// Open the directory with a file descriptor of at least `min_fd`.
+    ///
+    /// `reopen()` replaces the file descriptor of the directory in the child process, so it must
+    /// not be one that is also used for something else there - such as the target of a remap.
+    pub(crate) fn open(dir_path: &'static CStr, min_fd: RawFd) -> io::Result<OpenDir> {
+        let mut fd = unsafe {
+            libc::open(
+                dir_path.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            )
+        };
+        if fd == -1 {
+            return Err(error::last_os_error(Phase::OpenDir, None));
+        }
+
+        if fd < min_fd {
+            let moved_fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, min_fd) };
+            let err = error::last_os_error(Phase::OpenDir, Some(fd));
+            let _ = unsafe { libc::close(fd) };
+            if moved_fd == -1 {
+                return Err(err);
+            }
+            fd = moved_fd;
+        }
+
+        let dir = unsafe { libc::fdopendir(fd) };
+        if dir.is_null() {
+            let err = error::last_os_error(Phase::OpenDir, Some(fd));
+            let _ = unsafe { libc::close(fd) };
+            return Err(err);
+        }
+        Ok(OpenDir {
+            path: dir_path,
+            dir,
+        })
+    }
+
+    pub(crate) fn path(&self) -> &'static CStr {
+        self.path
+    }
+
+    pub(crate) fn fd(&self) -> RawFd {
+        unsafe { libc::dirfd(self.dir) }
+    }
+
+    // The DIR was opened by the parent process, so its file descriptor shares
+    // its file offset with the parent and with every other child process that
+    // was forked with it. Replace it with a freshly opened file descriptor so
+    // that child processes reading the directory at the same time don't move
+    // each other's offset and skip entries.
+    fn reopen(&mut self) -> io::Result<()> {
+        let fd = unsafe {
+            libc::open(
+                self.path.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            )
+        };
+        if fd == -1 {
+            return Err(error::last_os_error(Phase::OpenDir, None));
+        }
+
+        let dir_fd = self.fd();
+        let result = if unsafe { libc::dup2(fd, dir_fd) } == -1 {
+            Err(error::last_os_error(Phase::OpenDir, Some(dir_fd)))
+        } else {
+            // dup2() doesn't copy the FD_CLOEXEC flag.
+            set_cloexec(dir_fd, true)
+        };
+        let _ = unsafe { libc::close(fd) };
+        result
+    }
+
+    /// Call `f` with every file descriptor listed in the directory, except for
+    /// the file descriptor that is used to read the directory itself.
+    pub(crate) fn for_each_fd<F>(&mut self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(RawFd) -> io::Result<()>,
+    {
+        self.reopen()?;
+        let dir_fd = self.fd();
+
+        unsafe {
+            errno::set_errno(errno::Errno(0));
+            libc::rewinddir(self.dir);
+            if errno::errno() != errno::Errno(0) {
+                return Err(error::last_os_error(Phase::Rewind, Some(dir_fd)));
+            }
+
+            loop {
+                errno::set_errno(errno::Errno(0));
+                let dir_entry = libc::readdir(self.dir);
+                if dir_entry.is_null() {
+                    if errno::errno() != errno::Errno(0) {
+                        return Err(error::last_os_error(Phase::ReadEntry, Some(dir_fd)));
+                    } else {
+                        break;
+                    }
+                }
+
+                // Skip the "." and ".." entries.
+                let name = (*dir_entry).d_name.as_ptr();
+                if *name == '.' as libc::c_char {
+                    continue;
+                }
+
+                let fd = pos_int_from_ascii(name)?;
+                if fd != dir_fd {
+                    f(fd)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for OpenDir {
+    fn drop(&mut self) {
+        // This will likely call free() - which is why CloseFds leaks its
+        // OpenDir instead of dropping it in a child process after fork().
+        let _ = unsafe { libc::closedir(self.dir) };
+    }
+}
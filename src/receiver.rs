@@ -0,0 +1,229 @@
+use std::{
+    env, io,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+};
+
+use crate::{brute_force, error, set_cloexec};
+
+/// The environment variable that lists the file descriptors that a child process inherited, for
+/// `Receiver::manifest()`.
+///
+/// Its value is a `:` separated list of `name=fd` entries, as created by
+/// `Receiver::manifest_value()`.
+pub const MANIFEST_VAR: &str = "CLOSEFDS_FDS";
+
+/// The first file descriptor that is passed with socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Takes ownership of the file descriptors that the current process inherited from its parent,
+/// as advertised in its environment.
+///
+/// This is the counterpart of `SocketActivation` and of `CloseFdsBuilder::remap()` for the
+/// spawned process. Every advertised file descriptor must be open, and `FD_CLOEXEC` is set on
+/// them by default so that they aren't passed on any further.
+///
+/// The environment variables are removed once they have been read so that the file descriptors
+/// can only be received once. Like `std::env::remove_var()`, this must not race with other
+/// threads that access the environment, so it is best done early in `main()`.
+///
+/// # Example
+///
+/// ```no_run
+/// # use closefds::Receiver;
+/// # use std::net::TcpListener;
+/// # fn main() -> std::io::Result<()> {
+/// let mut fds = Receiver::new().listen_fds()?;
+/// let http = TcpListener::from(fds.take("http").expect("no http socket"));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Receiver {
+    cloexec: bool,
+}
+
+impl Default for Receiver {
+    fn default() -> Self {
+        Receiver::new()
+    }
+}
+
+impl Receiver {
+    /// Create a `Receiver` that sets `FD_CLOEXEC` on the received file descriptors.
+    pub fn new() -> Self {
+        Receiver { cloexec: true }
+    }
+
+    /// Whether to set `FD_CLOEXEC` on the received file descriptors.
+    pub fn cloexec(mut self, cloexec: bool) -> Self {
+        self.cloexec = cloexec;
+        self
+    }
+
+    /// Receive the file descriptors that were passed with socket activation.
+    ///
+    /// `LISTEN_FDS` is the number of file descriptors, starting at 3, and `LISTEN_FDNAMES` their
+    /// `:` separated names. File descriptors without a name are called `unknown`, like systemd
+    /// does. If `LISTEN_PID` is set to a different pid than the one of the current process, the
+    /// variables were meant for another process and nothing is received.
+    pub fn listen_fds(&self) -> io::Result<ReceivedFds> {
+        let listen_pid = take_var("LISTEN_PID")?;
+        let listen_fds = take_var("LISTEN_FDS")?;
+        let names = take_var("LISTEN_FDNAMES")?;
+
+        let count = match listen_fds {
+            Some(count) => parse_fd(&count)?,
+            None => return Ok(ReceivedFds::default()),
+        };
+        // No more file descriptors can be open than the limit allows, so a larger count is
+        // malformed - and shouldn't be collected before the first missing one is found.
+        let fd_limit = brute_force::fd_limit().map_err(error::decode)?;
+        if count > fd_limit || count > RawFd::MAX - SD_LISTEN_FDS_START {
+            return Err(invalid(&format!("LISTEN_FDS={}", count)));
+        }
+        if let Some(pid) = listen_pid {
+            if pid != std::process::id().to_string() {
+                return Ok(ReceivedFds::default());
+            }
+        }
+
+        let mut names = names.as_deref().unwrap_or("").split(':');
+        let fds = (0..count)
+            .map(|i| {
+                let name = names.next().filter(|name| !name.is_empty());
+                (
+                    name.unwrap_or("unknown").to_owned(),
+                    SD_LISTEN_FDS_START + i,
+                )
+            })
+            .collect();
+        self.receive(fds)
+    }
+
+    /// Receive the file descriptors that are listed in the `var` environment variable, which is
+    /// usually `MANIFEST_VAR`.
+    pub fn manifest(&self, var: &str) -> io::Result<ReceivedFds> {
+        let manifest = match take_var(var)? {
+            Some(manifest) => manifest,
+            None => return Ok(ReceivedFds::default()),
+        };
+
+        let mut fds = Vec::new();
+        for entry in manifest.split(':').filter(|entry| !entry.is_empty()) {
+            let (name, fd) = entry.rsplit_once('=').ok_or_else(|| invalid(entry))?;
+            fds.push((name.to_owned(), parse_fd(fd)?));
+        }
+        self.receive(fds)
+    }
+
+    /// Create the value of a manifest environment variable that lists `fds` as the file
+    /// descriptors that the child process will find them at - for example, the targets of
+    /// `CloseFdsBuilder::remap()`.
+    pub fn manifest_value<'a, I>(fds: I) -> io::Result<String>
+    where
+        I: IntoIterator<Item = (&'a str, RawFd)>,
+    {
+        let mut manifest = String::new();
+        for (name, fd) in fds {
+            if name.contains(':') || fd < 0 {
+                return Err(invalid(name));
+            }
+            if !manifest.is_empty() {
+                manifest.push(':');
+            }
+            manifest.push_str(&format!("{}={}", name, fd));
+        }
+        Ok(manifest)
+    }
+
+    fn receive(&self, fds: Vec<(String, RawFd)>) -> io::Result<ReceivedFds> {
+        // Check every file descriptor before taking ownership of any of them, so that nothing is
+        // closed twice if one of them is missing.
+        for (i, &(_, fd)) in fds.iter().enumerate() {
+            if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+                let err = io::Error::last_os_error();
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("inherited file descriptor {} isn't open: {}", fd, err),
+                ));
+            }
+            if fds[..i].iter().any(|&(_, prev_fd)| prev_fd == fd) {
+                return Err(invalid(&fd.to_string()));
+            }
+        }
+
+        let fds: Vec<(String, OwnedFd)> = fds
+            .into_iter()
+            .map(|(name, fd)| (name, unsafe { OwnedFd::from_raw_fd(fd) }))
+            .collect();
+        if self.cloexec {
+            for (_, fd) in &fds {
+                set_cloexec(fd.as_raw_fd(), true).map_err(error::decode)?;
+            }
+        }
+        Ok(ReceivedFds { fds })
+    }
+}
+
+/// The file descriptors that were received by a `Receiver`, in the order that they were
+/// advertised. Several file descriptors may have the same name.
+#[derive(Debug, Default)]
+pub struct ReceivedFds {
+    fds: Vec<(String, OwnedFd)>,
+}
+
+impl ReceivedFds {
+    /// Take the first remaining file descriptor called `name`.
+    pub fn take(&mut self, name: &str) -> Option<OwnedFd> {
+        let index = self.fds.iter().position(|(fd_name, _)| fd_name == name)?;
+        Some(self.fds.remove(index).1)
+    }
+
+    /// The number of remaining file descriptors.
+    pub fn len(&self) -> usize {
+        self.fds.len()
+    }
+
+    /// Whether there are no remaining file descriptors.
+    pub fn is_empty(&self) -> bool {
+        self.fds.is_empty()
+    }
+
+    /// The names of the remaining file descriptors.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.fds.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// All remaining file descriptors with their names.
+    pub fn into_vec(self) -> Vec<(String, OwnedFd)> {
+        self.fds
+    }
+}
+
+fn take_var(var: &str) -> io::Result<Option<String>> {
+    match env::var(var) {
+        Ok(value) => {
+            env::remove_var(var);
+            Ok(Some(value))
+        }
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => {
+            env::remove_var(var);
+            Err(invalid(var))
+        }
+    }
+}
+
+fn parse_fd(value: &str) -> io::Result<RawFd> {
+    match value.parse() {
+        Ok(fd) if fd >= 0 => Ok(fd),
+        _ => Err(invalid(value)),
+    }
+}
+
+fn invalid(value: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid inherited file descriptor entry: {:?}", value),
+    )
+}
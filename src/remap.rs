@@ -0,0 +1,112 @@
+use std::{io, os::unix::io::RawFd};
+
+use crate::{
+    close,
+    error::{self, Phase},
+};
+
+/// File descriptors to move to other numbers in the child process before the open file
+/// descriptors are processed.
+///
+/// Every source is first duplicated to a temporary file descriptor above all of the targets and
+/// only then moved to its target. No target can overwrite a source that hasn't been duplicated
+/// yet, which makes cycles - such as swapping 3 and 4 - and sources that are also targets work
+/// without having to order the moves.
+#[derive(Debug, Default)]
+pub(crate) struct Remap {
+    /// `(source, target)` pairs.
+    fds: Vec<(RawFd, RawFd)>,
+    /// The temporary file descriptors, allocated in the parent process.
+    temps: Box<[RawFd]>,
+}
+
+impl Remap {
+    pub(crate) fn new(fds: Vec<(RawFd, RawFd)>) -> io::Result<Remap> {
+        for (i, &(from, to)) in fds.iter().enumerate() {
+            if from < 0 || to < 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("can't remap file descriptor {} to {}", from, to),
+                ));
+            }
+            if fds[..i].iter().any(|&(_, prev_to)| prev_to == to) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "file descriptor {} is the target of more than one remap",
+                        to
+                    ),
+                ));
+            }
+        }
+
+        let temps = vec![-1; fds.len()].into_boxed_slice();
+        Ok(Remap { fds, temps })
+    }
+
+    /// The `(source, target)` pairs.
+    pub(crate) fn fds(&self) -> &[(RawFd, RawFd)] {
+        &self.fds
+    }
+
+    /// The file descriptors that the sources are moved to.
+    pub(crate) fn targets(&self) -> impl Iterator<Item = RawFd> + '_ {
+        self.fds.iter().map(|&(_, to)| to)
+    }
+
+    /// The lowest file descriptor number above every source and target.
+    pub(crate) fn min_unused_fd(&self) -> RawFd {
+        self.fds
+            .iter()
+            .map(|&(from, to)| from.max(to).saturating_add(1))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Move every source to its target. The targets don't have `FD_CLOEXEC` set afterwards.
+    /// The sources are left open.
+    pub(crate) fn apply(&mut self) -> io::Result<()> {
+        let min_temp = match self.targets().max() {
+            Some(max_target) => max_target.saturating_add(1),
+            None => return Ok(()),
+        };
+
+        let result = self.apply_with_temps(min_temp);
+        for temp in self.temps.iter_mut() {
+            if *temp != -1 {
+                let _ = close(*temp);
+                *temp = -1;
+            }
+        }
+        result
+    }
+
+    fn apply_with_temps(&mut self, min_temp: RawFd) -> io::Result<()> {
+        for (&(from, _), temp) in self.fds.iter().zip(self.temps.iter_mut()) {
+            let fd = unsafe { libc::fcntl(from, libc::F_DUPFD_CLOEXEC, min_temp) };
+            if fd == -1 {
+                return Err(error::last_os_error(Phase::Remap, Some(from)));
+            }
+            *temp = fd;
+        }
+
+        for (&(_, to), &temp) in self.fds.iter().zip(self.temps.iter()) {
+            // dup2() clears FD_CLOEXEC on the target. The temporary file descriptor is never
+            // equal to the target, so this also works for file descriptors that are remapped
+            // to themselves.
+            loop {
+                if unsafe { libc::dup2(temp, to) } != -1 {
+                    break;
+                }
+                let err = error::last_os_error(Phase::Remap, Some(to));
+                // Linux returns EBUSY if the target is being opened concurrently, which can't
+                // happen in a single threaded child, but retrying is what's recommended.
+                match error::errno(&err) {
+                    Some(libc::EINTR) | Some(libc::EBUSY) => {}
+                    _ => return Err(err),
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+use std::{io, os::unix::io::RawFd};
+
+use crate::{
+    audit,
+    error::{self, CloseFdsError, Phase},
+};
+
+/// Set the `FD_CLOEXEC` flag on every open file descriptor of the current process except for
+/// the ones in `keep_fds`, and return the file descriptors whose flag was changed.
+///
+/// This is meant to be called early by programs that want to make sure that nothing they
+/// inherited from their own parent process, or that a C library opened during initialization,
+/// leaks into their child processes. The file descriptors are found like `audit()` finds them.
+/// The file descriptors in `keep_fds` are left alone - their flags aren't cleared.
+///
+/// Other threads may keep opening and closing file descriptors while this runs:
+///
+/// * File descriptors that are closed after the directory was read are skipped.
+/// * File descriptors that are opened after the directory was read aren't processed, unless
+///   they reuse the number of a file descriptor that was closed in the meantime. In that case,
+///   the new file descriptor gets the `FD_CLOEXEC` flag - which at worst means that it isn't
+///   inherited by child processes, never that something leaks.
+/// * Getting and setting the flags isn't atomic, so a concurrent change to the `FD_CLOEXEC`
+///   flag of the same file descriptor may be overwritten.
+///
+/// # Example
+///
+/// ```no_run
+/// let changed = closefds::set_cloexec_on_all_except(&[0, 1, 2])?;
+/// if !changed.is_empty() {
+///     eprintln!("inherited file descriptors {:?} without FD_CLOEXEC", changed);
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn set_cloexec_on_all_except(keep_fds: &[RawFd]) -> io::Result<Vec<RawFd>> {
+    let mut fds = Vec::new();
+    audit::for_each_fd(|fd| {
+        fds.push(fd);
+        Ok(())
+    })
+    .map_err(error::decode)?;
+
+    let mut changed = Vec::new();
+    for fd in fds {
+        if keep_fds.contains(&fd) {
+            continue;
+        }
+        let fd_flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if fd_flags == -1 {
+            match io::Error::last_os_error().raw_os_error() {
+                Some(libc::EBADF) => continue,
+                errno => return Err(fcntl_error(errno, fd)),
+            }
+        }
+        if fd_flags & libc::FD_CLOEXEC != 0 {
+            continue;
+        }
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, fd_flags | libc::FD_CLOEXEC) } == -1 {
+            match io::Error::last_os_error().raw_os_error() {
+                Some(libc::EBADF) => continue,
+                errno => return Err(fcntl_error(errno, fd)),
+            }
+        }
+        changed.push(fd);
+    }
+    Ok(changed)
+}
+
+fn fcntl_error(errno: Option<i32>, fd: RawFd) -> io::Error {
+    CloseFdsError::new(Phase::Fcntl, errno.unwrap_or(libc::EINVAL), Some(fd)).into()
+}
@@ -0,0 +1,188 @@
+use std::{
+    io,
+    os::unix::io::{AsFd, AsRawFd},
+    process::Child,
+    ptr,
+};
+
+use crate::{exec_args::ExecArgs, CloseFds, CloseFdsBuilder, Program};
+
+/// The first file descriptor that is passed with socket activation.
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Room for any `pid_t`, which is a 32-bit signed integer.
+const LISTEN_PID_PLACEHOLDER: &str = "0000000000";
+
+/// Pass listening sockets to a child process the way that systemd's socket activation does.
+///
+/// The sockets are moved to consecutive file descriptors starting at 3, in the order that they
+/// were added, and every other file descriptor except for STDIN, STDOUT, and STDERR is handled
+/// like `CloseFds::builder()` does. The child process' environment gets `LISTEN_FDS` set to the
+/// number of sockets, `LISTEN_FDNAMES` set to their names, and `LISTEN_PID` set to the child's
+/// own pid.
+///
+/// The pid isn't known before `fork()`, so the environment is prepared in the parent process
+/// with a placeholder for `LISTEN_PID` that is overwritten in place in the child process,
+/// without allocating.
+///
+/// # Example
+///
+/// ```no_run
+/// # use closefds::{Program, SocketActivation};
+/// # use std::net::TcpListener;
+/// # fn main() -> std::io::Result<()> {
+/// let http = TcpListener::bind("127.0.0.1:8080")?;
+/// let https = TcpListener::bind("127.0.0.1:8443")?;
+/// let child = SocketActivation::new()
+///     .socket("http", &http)
+///     .socket("https", &https)
+///     .spawn(&Program::new("path/to/service"))?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct SocketActivation<'fd> {
+    builder: CloseFdsBuilder<'fd>,
+    names: Vec<String>,
+}
+
+impl SocketActivation<'static> {
+    /// Create a `SocketActivation` that passes no sockets yet and keeps STDIN, STDOUT, and
+    /// STDERR open.
+    pub fn new() -> Self {
+        SocketActivation::with_builder(CloseFds::builder().keep_stdio(true))
+    }
+}
+
+impl Default for SocketActivation<'static> {
+    fn default() -> Self {
+        SocketActivation::new()
+    }
+}
+
+impl<'fd> SocketActivation<'fd> {
+    /// Create a `SocketActivation` that handles the file descriptors other than the sockets
+    /// like `builder` does. File descriptors from 3 up are replaced by the sockets, so `builder`
+    /// must not remap anything to them.
+    pub fn with_builder(builder: CloseFdsBuilder<'fd>) -> Self {
+        SocketActivation {
+            builder,
+            names: Vec::new(),
+        }
+    }
+
+    /// Pass `socket` to the child process with the name `name`.
+    ///
+    /// Like with systemd, names consist of up to 255 printable ASCII characters other than
+    /// `:`. Spawning the child process fails if a name isn't valid.
+    pub fn socket<F: AsFd + ?Sized>(mut self, name: &str, socket: &'fd F) -> Self {
+        let to = SD_LISTEN_FDS_START + self.names.len() as i32;
+        self.builder = self.builder.remap(socket.as_fd().as_raw_fd(), to);
+        self.names.push(name.to_owned());
+        self
+    }
+
+    /// Spawn `program` with the sockets, like `CloseFdsBuilder::spawn()` does.
+    ///
+    /// The child process runs `program` with exactly the settings of the `Program`, plus the
+    /// socket activation variables. Like with `CloseFdsBuilder::posix_spawn()`, its standard
+    /// streams are the file descriptors 0, 1, and 2 of the plan, so use `with_builder()` and
+    /// `remap()` to redirect them.
+    pub fn spawn(self, program: &Program) -> io::Result<Child> {
+        for name in &self.names {
+            if !is_valid_name(name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid socket name: {:?}", name),
+                ));
+            }
+        }
+
+        let mut exec = Exec::new(program, &self.names)?;
+        self.builder
+            .spawn_with(&mut program.command(), move || exec.exec())
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 255
+        && name.bytes().all(|b| b.is_ascii_graphic() || b == b' ')
+        && !name.contains(':')
+}
+
+/// The `execvp()` call for the child process, prepared in the parent process.
+///
+/// `Command` only switches to the environment of the child process after it has run the
+/// `pre_exec()` functions, and the pid of the child process isn't known before `fork()`. So
+/// the child process patches the pid into a placeholder in its copy of this environment and
+/// calls `execvp()` itself, without allocating.
+struct Exec {
+    args: ExecArgs,
+    /// The placeholder digits of the `LISTEN_PID` entry in the environment.
+    listen_pid: *mut u8,
+}
+
+// The pointer only points into the environment that the Exec owns.
+unsafe impl Send for Exec {}
+unsafe impl Sync for Exec {}
+
+impl Exec {
+    fn new(program: &Program, names: &[String]) -> io::Result<Exec> {
+        let args = ExecArgs::from_program(
+            program,
+            &[
+                ("LISTEN_FDS", &names.len().to_string()),
+                ("LISTEN_FDNAMES", &names.join(":")),
+                ("LISTEN_PID", LISTEN_PID_PLACEHOLDER),
+            ],
+        )?;
+        let listen_pid = args
+            .env_value_ptr("LISTEN_PID")
+            .expect("LISTEN_PID missing");
+
+        Ok(Exec { args, listen_pid })
+    }
+
+    /// Set `LISTEN_PID` to the pid of the calling process and replace it with the program.
+    /// Only returns if `execvp()` fails.
+    fn exec(&mut self) -> io::Result<()> {
+        let mut digits = [0u8; LISTEN_PID_PLACEHOLDER.len()];
+        let mut pid = unsafe { libc::getpid() } as u32;
+        let mut len = 0;
+        loop {
+            digits[len] = b'0' + (pid % 10) as u8;
+            len += 1;
+            pid /= 10;
+            if pid == 0 {
+                break;
+            }
+        }
+
+        unsafe {
+            // The placeholder is long enough for any pid, and the entry stays NUL terminated.
+            for i in 0..len {
+                *self.listen_pid.add(i) = digits[len - 1 - i];
+            }
+            *self.listen_pid.add(len) = 0;
+
+            // execvp() looks up the program in the PATH of the new environment.
+            *environ() = self.args.envp() as *mut *mut libc::c_char;
+            libc::execvp(self.args.program().as_ptr(), self.args.argv());
+        }
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+unsafe fn environ() -> *mut *mut *mut libc::c_char {
+    extern "C" {
+        static mut environ: *mut *mut libc::c_char;
+    }
+    ptr::addr_of_mut!(environ)
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+unsafe fn environ() -> *mut *mut *mut libc::c_char {
+    libc::_NSGetEnviron()
+}
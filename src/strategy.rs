@@ -0,0 +1,230 @@
+use std::{ffi::CStr, fmt, io, os::unix::io::RawFd};
+
+use crate::{brute_force, error, readdir::OpenDir, DEV_FD_DIR_NAME};
+#[cfg(target_os = "linux")]
+use crate::{close_range, getdents::GetdentsDir, FALLBACK_FD_DIR_NAME, FD_DIR_NAME};
+
+/// The mechanism that is used to find and process the open file descriptors of the child process.
+///
+/// All strategies except for `BruteForce` are guaranteed to process every open file descriptor
+/// or to fail. `probe()` reports which strategies work on the current system.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Strategy {
+    /// Use the best strategy that is available. On Linux, this is `CloseRange` with a fallback
+    /// to `Getdents` if `close_range()` isn't supported. On other operating systems, this is
+    /// `DevFd`.
+    #[default]
+    Auto,
+
+    /// Use `close_range()` with the `CLOSE_RANGE_CLOEXEC` flag. Linux 5.11 or later only.
+    CloseRange,
+
+    /// Read `/proc/thread-self/fd/` with the raw `getdents64()` system call. Linux only.
+    Getdents,
+
+    /// Read `/proc/thread-self/fd/` with `readdir()`. Linux only.
+    ProcfsReaddir,
+
+    /// Read `/dev/fd/` with `readdir()`. On FreeBSD, this requires `fdescfs` to be mounted.
+    DevFd,
+
+    /// Check every file descriptor number from 0 up to the soft `RLIMIT_NOFILE` limit.
+    ///
+    /// This is never picked by `Auto` since it will miss any file descriptors that were opened
+    /// before the limit was lowered, and because it is slow if the limit is high.
+    BruteForce,
+}
+
+impl Strategy {
+    /// All strategies other than `Auto`, in the order that they are preferred.
+    const ALL: [Strategy; 5] = [
+        Strategy::CloseRange,
+        Strategy::Getdents,
+        Strategy::ProcfsReaddir,
+        Strategy::DevFd,
+        Strategy::BruteForce,
+    ];
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Strategy::Auto => "auto",
+            Strategy::CloseRange => "close_range",
+            Strategy::Getdents => "getdents",
+            Strategy::ProcfsReaddir => "procfs readdir",
+            Strategy::DevFd => "/dev/fd readdir",
+            Strategy::BruteForce => "brute force",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The resources that a strategy needs in the child process. These are all created in the
+/// parent process.
+pub(crate) enum Backend {
+    /// `close_range()`, with the directory to walk instead if `close_range()` isn't supported.
+    #[cfg(target_os = "linux")]
+    CloseRange(Option<GetdentsDir>),
+    #[cfg(target_os = "linux")]
+    Getdents(GetdentsDir),
+    Readdir(OpenDir),
+    BruteForce,
+}
+
+impl Backend {
+    /// Create the resources for `strategy`. File descriptors that the child process replaces are
+    /// opened at `min_fd` or above.
+    pub(crate) fn new(strategy: Strategy, min_fd: RawFd) -> io::Result<Backend> {
+        Backend::open(strategy, min_fd).map_err(error::decode)
+    }
+
+    fn open(strategy: Strategy, min_fd: RawFd) -> io::Result<Backend> {
+        match strategy {
+            #[cfg(target_os = "linux")]
+            Strategy::Auto => Ok(Backend::CloseRange(Some(open_proc_fd_dir(
+                GetdentsDir::open,
+            )?))),
+            #[cfg(not(target_os = "linux"))]
+            Strategy::Auto => Backend::open(Strategy::DevFd, min_fd),
+            #[cfg(target_os = "linux")]
+            Strategy::CloseRange => Ok(Backend::CloseRange(None)),
+            #[cfg(target_os = "linux")]
+            Strategy::Getdents => Ok(Backend::Getdents(open_proc_fd_dir(GetdentsDir::open)?)),
+            #[cfg(target_os = "linux")]
+            Strategy::ProcfsReaddir => Ok(Backend::Readdir(open_proc_fd_dir(|path| {
+                OpenDir::open(path, min_fd)
+            })?)),
+            Strategy::DevFd => Ok(Backend::Readdir(OpenDir::open(
+                CStr::from_bytes_with_nul(DEV_FD_DIR_NAME).expect("Invalid Path"),
+                min_fd,
+            )?)),
+            Strategy::BruteForce => Ok(Backend::BruteForce),
+            #[cfg(not(target_os = "linux"))]
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("the {} strategy is only available on Linux", strategy),
+            )),
+        }
+    }
+
+    /// Call `f` with every open file descriptor. Returns `None` for `close_range()`, which
+    /// doesn't look at individual file descriptors.
+    pub(crate) fn for_each_fd<F>(&mut self, f: F) -> Option<io::Result<()>>
+    where
+        F: FnMut(RawFd) -> io::Result<()>,
+    {
+        match *self {
+            #[cfg(target_os = "linux")]
+            Backend::CloseRange(_) => None,
+            #[cfg(target_os = "linux")]
+            Backend::Getdents(ref mut dir) => Some(dir.for_each_fd(f)),
+            Backend::Readdir(ref mut dir) => Some(dir.for_each_fd(f)),
+            Backend::BruteForce => Some(brute_force::for_each_fd(f)),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn open_proc_fd_dir<D, F>(open: F) -> io::Result<D>
+where
+    F: Fn(&'static CStr) -> io::Result<D>,
+{
+    open(CStr::from_bytes_with_nul(FD_DIR_NAME).expect("Invalid Path")).or_else(|err| {
+        if error::errno(&err) == Some(libc::ENOENT) {
+            open(CStr::from_bytes_with_nul(FALLBACK_FD_DIR_NAME).expect("Invalid Path"))
+        } else {
+            Err(err)
+        }
+    })
+}
+
+/// Whether a `Strategy` works on the current system, as reported by `probe()`.
+#[derive(Debug)]
+pub struct ProbeResult {
+    strategy: Strategy,
+    result: io::Result<()>,
+}
+
+impl ProbeResult {
+    /// The strategy that was probed.
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// Whether the strategy works on the current system.
+    pub fn is_supported(&self) -> bool {
+        self.result.is_ok()
+    }
+
+    /// Why the strategy doesn't work on the current system, if it doesn't.
+    pub fn error(&self) -> Option<&io::Error> {
+        self.result.as_ref().err()
+    }
+}
+
+/// Check which strategies work on the current system.
+///
+/// Every strategy other than `Strategy::Auto` is tried out in the calling process, without
+/// modifying any file descriptors, and the results are returned in order of preference. The
+/// first supported strategy other than `Strategy::BruteForce` is the one that `Strategy::Auto`
+/// will use.
+///
+/// Note that the results describe the calling process. A child process may see a different
+/// system if, for example, it is spawned into a new mount namespace or with a different seccomp
+/// filter.
+///
+/// # Example
+///
+/// ```no_run
+/// for result in closefds::probe() {
+///     match result.error() {
+///         None => println!("{}: supported", result.strategy()),
+///         Some(err) => println!("{}: not supported: {}", result.strategy(), err),
+///     }
+/// }
+/// ```
+pub fn probe() -> Vec<ProbeResult> {
+    Strategy::ALL
+        .iter()
+        .map(|&strategy| ProbeResult {
+            strategy,
+            result: probe_strategy(strategy).map_err(error::decode),
+        })
+        .collect()
+}
+
+fn probe_strategy(strategy: Strategy) -> io::Result<()> {
+    let mut backend = Backend::new(strategy, 0)?;
+    match backend {
+        #[cfg(target_os = "linux")]
+        Backend::CloseRange(_) => close_range::probe(),
+        Backend::Readdir(ref mut dir) => {
+            // A directory that doesn't list a file descriptor that we know is
+            // open doesn't list all open file descriptors - for example,
+            // /dev/fd on FreeBSD without fdescfs only lists 0, 1, and 2.
+            let probe_fd = unsafe { libc::fcntl(dir.fd(), libc::F_DUPFD_CLOEXEC, 3) };
+            if probe_fd == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut found_probe_fd = false;
+            let result = dir.for_each_fd(|fd| {
+                found_probe_fd |= fd == probe_fd;
+                Ok(())
+            });
+            let _ = unsafe { libc::close(probe_fd) };
+            result?;
+            if !found_probe_fd {
+                return Err(io::Error::other(format!(
+                    "{} doesn't list all open file descriptors",
+                    dir.path().to_string_lossy()
+                )));
+            }
+            Ok(())
+        }
+        _ => backend
+            .for_each_fd(|_| Ok(()))
+            .expect("Strategy doesn't walk file descriptors"),
+    }
+}
@@ -0,0 +1,258 @@
+//! Helpers for checking which file descriptors a process has open and which ones a child process
+//! inherits, for use in test suites. Requires the `testing` feature.
+//!
+//! Other threads that open or close file descriptors at the same time - such as other tests that
+//! run in parallel - affect the results, so tests that use these helpers should run on their
+//! own or only look at file descriptors that they created themselves.
+
+use std::{
+    collections::BTreeSet,
+    convert::TryInto,
+    fs::File,
+    io::{self, Read},
+    mem,
+    os::unix::{
+        io::{FromRawFd, RawFd},
+        process::CommandExt,
+    },
+    process::{Child, Command},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{audit, error, strategy::Backend, CloseFdsBuilder, Strategy};
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+use crate::{set_cloexec, ForkLock};
+
+/// The open file descriptors of the current process at one point in time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FdSnapshot {
+    fds: BTreeSet<RawFd>,
+}
+
+impl FdSnapshot {
+    /// Take a snapshot of the open file descriptors of the current process.
+    pub fn take() -> io::Result<FdSnapshot> {
+        let mut fds = BTreeSet::new();
+        audit::for_each_fd(|fd| {
+            fds.insert(fd);
+            Ok(())
+        })
+        .map_err(error::decode)?;
+        Ok(FdSnapshot { fds })
+    }
+
+    /// The file descriptors that were open.
+    pub fn fds(&self) -> &BTreeSet<RawFd> {
+        &self.fds
+    }
+
+    /// The file descriptors that are open in this snapshot but weren't in `earlier`.
+    pub fn new_since(&self, earlier: &FdSnapshot) -> Vec<RawFd> {
+        self.fds.difference(&earlier.fds).copied().collect()
+    }
+}
+
+/// Call `f` and panic if the current process has any file descriptors open afterwards that it
+/// didn't have open before.
+///
+/// # Example
+///
+/// ```no_run
+/// # use closefds::testing::assert_no_new_fds;
+/// let contents = assert_no_new_fds(|| std::fs::read("Cargo.toml"));
+/// ```
+pub fn assert_no_new_fds<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let before = FdSnapshot::take().expect("Failed to list open file descriptors");
+    let result = f();
+    let after = FdSnapshot::take().expect("Failed to list open file descriptors");
+
+    let new_fds = after.new_since(&before);
+    if !new_fds.is_empty() {
+        let infos: Vec<String> = audit::audit()
+            .unwrap_or_default()
+            .iter()
+            .filter(|info| new_fds.contains(&info.fd()))
+            .map(|info| info.to_string())
+            .collect();
+        panic!(
+            "new file descriptors {:?} were left open: {:?}",
+            new_fds, infos
+        );
+    }
+    result
+}
+
+/// Spawn `cmd` and return the file descriptors that its child process would inherit.
+///
+/// The program of `cmd` isn't run. Instead, a `pre_exec()` function that runs after the ones
+/// that `cmd` already has lists the open file descriptors that don't have `FD_CLOEXEC` set,
+/// reports them to this process, and exits. That function stays installed in `cmd` afterwards,
+/// but it does nothing once this function has returned. On Linux, it doesn't keep any file
+/// descriptor open. Elsewhere, it keeps the one it uses to read `/dev/fd/` open until `cmd` is
+/// dropped.
+///
+/// `cmd` must not have been spawned by this crate before, since the closures that that leaves
+/// in it fail with `EBADF`.
+///
+/// # Example
+///
+/// ```no_run
+/// # use closefds::{close_fds_on_exec, testing::probe_command};
+/// # use std::{os::unix::process::CommandExt, process::Command};
+/// # fn main() -> std::io::Result<()> {
+/// let mut cmd = Command::new("path/to/program");
+/// unsafe {
+///     cmd.pre_exec(close_fds_on_exec(vec![0, 1, 2, 3])?);
+/// }
+/// assert!(probe_command(&mut cmd)?.iter().all(|&fd| fd <= 3));
+/// # Ok(())
+/// # }
+/// ```
+pub fn probe_command(cmd: &mut Command) -> io::Result<Vec<RawFd>> {
+    let (pipe, mut report) = Probe::new()?;
+    let armed = Arc::new(AtomicBool::new(true));
+    let func = {
+        let armed = armed.clone();
+        move || {
+            if armed.load(Ordering::SeqCst) {
+                report();
+            }
+            Ok(())
+        }
+    };
+
+    // The closure only uses functions that are safe to call after fork().
+    unsafe {
+        cmd.pre_exec(func);
+    }
+    let spawned = cmd.spawn();
+    armed.store(false, Ordering::SeqCst);
+    pipe.collect(spawned)
+}
+
+/// Like `probe_command()`, but with the file descriptors processed the way that
+/// `builder.spawn(cmd)` would process them.
+pub fn probe_builder(builder: CloseFdsBuilder, cmd: &mut Command) -> io::Result<Vec<RawFd>> {
+    let (pipe, mut report) = Probe::new()?;
+    let spawned = builder.run_with(
+        cmd,
+        move || {
+            report();
+            Ok(())
+        },
+        Command::spawn,
+    );
+    pipe.collect(spawned)
+}
+
+/// Create a pipe with `FD_CLOEXEC` set on both ends, so that no other child process inherits it.
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+fn cloexec_pipe() -> io::Result<[RawFd; 2]> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fds)
+}
+
+/// Without `pipe2()`, hold a read guard of the `ForkLock` until `FD_CLOEXEC` is set, so that child
+/// processes that other threads spawn don't inherit the pipe in between.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn cloexec_pipe() -> io::Result<[RawFd; 2]> {
+    let _guard = ForkLock::read();
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    for &fd in &fds {
+        if let Err(err) = set_cloexec(fd, true) {
+            unsafe {
+                libc::close(fds[0]);
+                libc::close(fds[1]);
+            }
+            return Err(error::decode(err));
+        }
+    }
+    Ok(fds)
+}
+
+/// The read end of the pipe that the probe child reports its file descriptors over, along with
+/// the write end until the child has been spawned.
+struct Probe {
+    read: File,
+    write: File,
+}
+
+impl Probe {
+    /// Create the pipe, and the function that runs in the child process and never returns.
+    fn new() -> io::Result<(Probe, impl FnMut() + Send + Sync + 'static)> {
+        let strategy = if cfg!(target_os = "linux") {
+            Strategy::Getdents
+        } else {
+            Strategy::DevFd
+        };
+        let mut backend = Backend::new(strategy, 0)?;
+
+        let fds = cloexec_pipe()?;
+        let probe = unsafe {
+            Probe {
+                read: File::from_raw_fd(fds[0]),
+                write: File::from_raw_fd(fds[1]),
+            }
+        };
+
+        let write_fd = fds[1];
+        let report = move || {
+            let result = backend.for_each_fd(|fd| {
+                let fd_flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+                if fd_flags == -1 || fd_flags & libc::FD_CLOEXEC != 0 {
+                    return Ok(());
+                }
+                let bytes = fd.to_ne_bytes();
+                let written = unsafe {
+                    libc::write(write_fd, bytes.as_ptr() as *const libc::c_void, bytes.len())
+                };
+                if written != bytes.len() as isize {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+            let status = match result {
+                Some(Ok(())) => 0,
+                _ => 1,
+            };
+            unsafe { libc::_exit(status) }
+        };
+
+        Ok((probe, report))
+    }
+
+    /// Read the reported file descriptors once the child process has been spawned.
+    fn collect(self, spawned: io::Result<Child>) -> io::Result<Vec<RawFd>> {
+        let Probe { mut read, write } = self;
+        drop(write);
+        let mut child = spawned?;
+
+        let mut bytes = Vec::new();
+        let read_result = read.read_to_end(&mut bytes);
+        let status = child.wait()?;
+        read_result?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "the probe child process failed: {}",
+                status
+            )));
+        }
+
+        Ok(bytes
+            .chunks_exact(mem::size_of::<RawFd>())
+            .map(|chunk| RawFd::from_ne_bytes(chunk.try_into().expect("Invalid Chunk")))
+            .collect())
+    }
+}
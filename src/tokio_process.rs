@@ -0,0 +1,58 @@
+use std::{
+    io,
+    process::{ExitStatus, Output},
+};
+
+use tokio::process::{Child, Command};
+
+use crate::{error, CloseFdsCommand, CommandExt};
+
+impl CommandExt for Command {
+    fn close_fds(&mut self) -> CloseFdsCommand<'_, 'static, Self> {
+        CloseFdsCommand::new(self)
+    }
+}
+
+impl<'cmd, 'fd> CloseFdsCommand<'cmd, 'fd, Command> {
+    /// Spawn the command like `tokio::process::Command::spawn()` does.
+    pub fn spawn(self) -> io::Result<Child> {
+        let CloseFdsCommand { cmd, builder } = self;
+        let (armed, func) = builder.armed_hook(|| Ok(()))?;
+
+        // The closure only uses functions that are safe to call after fork().
+        unsafe {
+            cmd.pre_exec(func);
+        }
+        let child = cmd.spawn();
+        drop(armed);
+        child.map_err(error::decode)
+    }
+
+    /// Run the command and collect its output like `tokio::process::Command::output()` does.
+    pub async fn output(self) -> io::Result<Output> {
+        let CloseFdsCommand { cmd, builder } = self;
+        let (armed, func) = builder.armed_hook(|| Ok(()))?;
+
+        unsafe {
+            cmd.pre_exec(func);
+        }
+        // output() spawns the child process right away, so the plan isn't needed while the
+        // output is collected.
+        let output = cmd.output();
+        drop(armed);
+        output.await.map_err(error::decode)
+    }
+
+    /// Run the command and wait for it to exit like `tokio::process::Command::status()` does.
+    pub async fn status(self) -> io::Result<ExitStatus> {
+        let CloseFdsCommand { cmd, builder } = self;
+        let (armed, func) = builder.armed_hook(|| Ok(()))?;
+
+        unsafe {
+            cmd.pre_exec(func);
+        }
+        let status = cmd.status();
+        drop(armed);
+        status.await.map_err(error::decode)
+    }
+}
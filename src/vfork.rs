@@ -0,0 +1,315 @@
+use std::{
+    ffi::{CStr, CString},
+    io, mem,
+    os::unix::ffi::OsStrExt,
+    ptr,
+};
+
+use crate::{error, exec_args::ExecArgs, posix_spawn::SpawnedChild, CloseFds, ForkLock, Program};
+
+/// Reset all signal handlers other than `SIG_IGN` to `SIG_DFL` in the child process. Linux 5.5
+/// and later.
+const CLONE_CLEAR_SIGHAND: u64 = 0x1_0000_0000;
+
+/// The stack of the child process. It only needs to be large enough to process the file
+/// descriptors and call `execve()`.
+const STACK_SIZE: usize = 256 * 1024;
+
+/// The first version of `struct clone_args`, which has every field that is used here.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[repr(C)]
+struct CloneArgs {
+    flags: u64,
+    pidfd: u64,
+    child_tid: u64,
+    parent_tid: u64,
+    exit_signal: u64,
+    stack: u64,
+    stack_size: u64,
+    tls: u64,
+}
+
+/// Everything that the child process needs, prepared by the parent process.
+///
+/// The child process shares its memory with the parent process until it calls `execve()` or
+/// exits, and the calling thread of the parent process doesn't run until then. So the child
+/// process reports errors by writing them to `error`, where the parent process finds them once
+/// it continues - this is what glibc's `posix_spawn()` does as well, and unlike a pipe it
+/// can't be lost if the file descriptor plan happens to keep the pipe open.
+struct ChildState<'a> {
+    close_fds: &'a mut CloseFds,
+    args: &'a ExecArgs,
+    /// The paths to try to execute, in order.
+    paths: &'a [CString],
+    cwd: Option<&'a CStr>,
+    error: libc::c_int,
+}
+
+/// Spawn `program` with `clone3()` or `clone()` with `CLONE_VM` and `CLONE_VFORK`.
+pub(crate) fn spawn(program: &Program, close_fds: &mut CloseFds) -> io::Result<SpawnedChild> {
+    let args = ExecArgs::from_program(program, &[])?;
+    let paths = args.paths()?;
+    let cwd = match program.get_current_dir() {
+        Some(cwd) => Some(
+            CString::new(cwd.as_os_str().as_bytes())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?,
+        ),
+        None => None,
+    };
+    let mut child = ChildState {
+        close_fds,
+        args: &args,
+        paths: &paths,
+        cwd: cwd.as_deref(),
+        error: 0,
+    };
+
+    let stack = Stack::new()?;
+
+    // clone() doesn't run the pthread_atfork() handlers that take the ForkLock.
+    let fork_guard = ForkLock::write();
+
+    // Signal handlers of the parent process must not run in the child process while it shares
+    // the parent's memory, so block every signal until the child process has reset them.
+    let mut old_mask = mem::MaybeUninit::uninit();
+    unsafe {
+        let mut all = mem::MaybeUninit::uninit();
+        libc::sigfillset(all.as_mut_ptr());
+        libc::pthread_sigmask(libc::SIG_SETMASK, all.as_ptr(), old_mask.as_mut_ptr());
+    }
+
+    let arg = &mut child as *mut ChildState as *mut libc::c_void;
+    let mut pid = unsafe { clone3(&stack, arg) };
+    if pid < 0 && [libc::ENOSYS, libc::EINVAL, libc::EPERM].contains(&-pid) {
+        // clone3() or CLONE_CLEAR_SIGHAND isn't supported. The child process resets the signal
+        // handlers itself either way.
+        pid = unsafe {
+            libc::clone(
+                child_main,
+                stack.top(),
+                libc::CLONE_VM | libc::CLONE_VFORK | libc::SIGCHLD,
+                arg,
+            )
+        };
+        if pid == -1 {
+            pid = -io::Error::last_os_error()
+                .raw_os_error()
+                .unwrap_or(libc::EINVAL);
+        }
+    }
+
+    unsafe {
+        libc::pthread_sigmask(libc::SIG_SETMASK, old_mask.as_ptr(), ptr::null_mut());
+    }
+    drop(fork_guard);
+    drop(stack);
+
+    if pid < 0 {
+        return Err(io::Error::from_raw_os_error(-pid));
+    }
+    let mut spawned = SpawnedChild::from_pid(pid, false);
+    if child.error != 0 {
+        // The child process exited without calling execve().
+        let _ = spawned.wait();
+        return Err(error::decode(io::Error::from_raw_os_error(child.error)));
+    }
+    Ok(spawned)
+}
+
+/// Runs in the child process, on the stack that was allocated by the parent process. Only
+/// returns if the child process fails to call `execve()`.
+extern "C" fn child_main(arg: *mut libc::c_void) -> libc::c_int {
+    let child = unsafe { &mut *(arg as *mut ChildState) };
+    child.error = exec_child(child);
+    127
+}
+
+fn exec_child(child: &mut ChildState) -> libc::c_int {
+    unsafe {
+        // Like Command, also reset SIGPIPE, which the Rust runtime ignores.
+        let mut action: libc::sigaction = mem::zeroed();
+        for signal in 1..libc::SIGRTMAX() + 1 {
+            let mut old_action = mem::MaybeUninit::<libc::sigaction>::uninit();
+            if libc::sigaction(signal, ptr::null(), old_action.as_mut_ptr()) != 0 {
+                continue;
+            }
+            let handler = old_action.assume_init().sa_sigaction;
+            if handler != libc::SIG_DFL && (handler != libc::SIG_IGN || signal == libc::SIGPIPE) {
+                action.sa_sigaction = libc::SIG_DFL;
+                libc::sigaction(signal, &action, ptr::null_mut());
+            }
+        }
+
+        if let Some(cwd) = child.cwd {
+            if libc::chdir(cwd.as_ptr()) == -1 {
+                return errno();
+            }
+        }
+    }
+
+    if let Err(err) = child.close_fds.before_exec() {
+        return err.raw_os_error().unwrap_or(libc::EINVAL);
+    }
+
+    unsafe {
+        let mut empty = mem::MaybeUninit::uninit();
+        libc::sigemptyset(empty.as_mut_ptr());
+        libc::sigprocmask(libc::SIG_SETMASK, empty.as_ptr(), ptr::null_mut());
+
+        // Like execvp(), report EACCES if any path was found but couldn't be executed, and the
+        // error of the last path otherwise.
+        let mut error = libc::ENOENT;
+        let mut seen_eacces = false;
+        for path in child.paths {
+            libc::execve(path.as_ptr(), child.args.argv(), child.args.envp());
+            error = errno();
+            match error {
+                libc::EACCES => seen_eacces = true,
+                libc::ENOENT | libc::ENOTDIR | libc::ENAMETOOLONG | libc::ELOOP => {}
+                _ => return error,
+            }
+        }
+        if seen_eacces {
+            libc::EACCES
+        } else {
+            error
+        }
+    }
+}
+
+fn errno() -> libc::c_int {
+    io::Error::last_os_error()
+        .raw_os_error()
+        .unwrap_or(libc::EINVAL)
+}
+
+/// Call `clone3()` so that the child process runs `child_main(arg)` on `stack`. Returns the pid
+/// of the child process or the negated error number.
+///
+/// The child process returns from the system call on a different stack, which Rust code can't
+/// deal with, so this needs assembly.
+#[cfg(target_arch = "x86_64")]
+unsafe fn clone3(stack: &Stack, arg: *mut libc::c_void) -> libc::c_int {
+    let args = clone_args(stack);
+    let ret: i64;
+    std::arch::asm!(
+        "syscall",
+        "test rax, rax",
+        "jnz 2f",
+        // The child process: call child_main(arg) and exit with its result.
+        "xor ebp, ebp",
+        "mov rdi, r13",
+        "call r12",
+        "mov edi, eax",
+        "mov eax, {exit}",
+        "syscall",
+        "ud2",
+        "2:",
+        exit = const libc::SYS_exit,
+        inlateout("rax") libc::SYS_clone3 => ret,
+        in("rdi") &args,
+        in("rsi") mem::size_of::<CloneArgs>(),
+        in("r12") child_main as *const () as usize,
+        in("r13") arg,
+        lateout("rcx") _,
+        lateout("r11") _,
+    );
+    ret as libc::c_int
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn clone3(stack: &Stack, arg: *mut libc::c_void) -> libc::c_int {
+    let args = clone_args(stack);
+    let ret: i64;
+    std::arch::asm!(
+        "svc 0",
+        "cbnz x0, 2f",
+        // The child process: call child_main(arg) and exit with its result.
+        "mov x29, xzr",
+        "mov x30, xzr",
+        "mov x0, x10",
+        "blr x9",
+        "mov x8, {exit}",
+        "svc 0",
+        "brk 0",
+        "2:",
+        exit = const libc::SYS_exit,
+        inlateout("x0") &args as *const CloneArgs as i64 => ret,
+        in("x1") mem::size_of::<CloneArgs>(),
+        in("x8") libc::SYS_clone3,
+        in("x9") child_main as *const () as usize,
+        in("x10") arg,
+    );
+    ret as libc::c_int
+}
+
+/// Without the assembly for `clone3()`, always use `clone()`.
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+unsafe fn clone3(_stack: &Stack, _arg: *mut libc::c_void) -> libc::c_int {
+    -libc::ENOSYS
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn clone_args(stack: &Stack) -> CloneArgs {
+    CloneArgs {
+        flags: (libc::CLONE_VM | libc::CLONE_VFORK) as u64 | CLONE_CLEAR_SIGHAND,
+        pidfd: 0,
+        child_tid: 0,
+        parent_tid: 0,
+        exit_signal: libc::SIGCHLD as u64,
+        stack: stack.bottom as u64,
+        stack_size: STACK_SIZE as u64,
+        tls: 0,
+    }
+}
+
+/// The stack of the child process, with a guard page below it.
+struct Stack {
+    /// The start of the mapping, which is the guard page.
+    map: *mut libc::c_void,
+    map_size: usize,
+    /// The lowest usable address of the stack.
+    bottom: *mut libc::c_void,
+}
+
+impl Stack {
+    fn new() -> io::Result<Stack> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let map_size = STACK_SIZE + page_size;
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                map_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_STACK,
+                -1,
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let stack = Stack {
+            map,
+            map_size,
+            bottom: unsafe { (map as *mut u8).add(page_size) as *mut libc::c_void },
+        };
+        if unsafe { libc::mprotect(map, page_size, libc::PROT_NONE) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(stack)
+    }
+
+    /// The stack grows down, so this is where it starts.
+    fn top(&self) -> *mut libc::c_void {
+        unsafe { (self.bottom as *mut u8).add(STACK_SIZE) as *mut libc::c_void }
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.map, self.map_size);
+        }
+    }
+}
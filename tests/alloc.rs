@@ -0,0 +1,174 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    net::TcpListener,
+    os::unix::process::CommandExt,
+    process::{Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+mod common;
+
+use closefds::{
+    close_fds_on_exec_with_strategy, probe, CloseFds, CloseFdsError, Mode, Program,
+    SocketActivation,
+};
+use common::{close, fork_and_run, pipe};
+
+/// An allocator that aborts when it is used by a child process after `fork()`.
+///
+/// Allocators like jemalloc protect their state with locks. If another thread holds one of them
+/// when `fork()` is called, it stays locked forever in the child process, and the child process
+/// deadlocks as soon as it allocates. `ALLOCATING` tracks whether another thread is inside the
+/// allocator, but since that depends on timing, any allocation in a child process aborts.
+struct ForkCheckingAlloc;
+
+/// The pid of the test process, or 0 before the test has started.
+static PARENT_PID: AtomicI32 = AtomicI32::new(0);
+static ALLOCATING: AtomicBool = AtomicBool::new(false);
+
+unsafe impl GlobalAlloc for ForkCheckingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        check_not_forked();
+        ALLOCATING.store(true, Ordering::SeqCst);
+        let ptr = System.alloc(layout);
+        ALLOCATING.store(false, Ordering::SeqCst);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        check_not_forked();
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        check_not_forked();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+fn check_not_forked() {
+    let parent_pid = PARENT_PID.load(Ordering::SeqCst);
+    if parent_pid != 0 && unsafe { libc::getpid() } != parent_pid {
+        let msg: &[u8] = if ALLOCATING.load(Ordering::SeqCst) {
+            b"used the allocator after fork() while another thread was allocating\n"
+        } else {
+            b"used the allocator after fork()\n"
+        };
+        unsafe {
+            libc::write(2, msg.as_ptr() as *const libc::c_void, msg.len());
+            libc::abort();
+        }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: ForkCheckingAlloc = ForkCheckingAlloc;
+
+fn status(cmd: &mut Command) -> ExitStatus {
+    cmd.stdout(Stdio::null()).status().unwrap()
+}
+
+#[test]
+fn no_allocations_after_fork() {
+    PARENT_PID.store(unsafe { libc::getpid() }, Ordering::SeqCst);
+
+    // Keep another thread busy allocating while the child processes are spawned.
+    let done = Arc::new(AtomicBool::new(false));
+    let allocator_thread = {
+        let done = done.clone();
+        thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                drop(vec![0u8; 4096]);
+            }
+        })
+    };
+
+    let list_fds = env!("CARGO_BIN_EXE_list_fds");
+    let (r, w) = pipe();
+
+    for result in probe() {
+        if !result.is_supported() {
+            continue;
+        }
+        let func = close_fds_on_exec_with_strategy(vec![0, 1, 2, w], result.strategy()).unwrap();
+        let mut cmd = Command::new(list_fds);
+        unsafe {
+            cmd.pre_exec(func);
+        }
+        assert!(status(&mut cmd).success(), "{}", result.strategy());
+
+        for &mode in &[Mode::CloseOnExec, Mode::Close] {
+            let mut cmd = Command::new(list_fds);
+            cmd.stdout(Stdio::null());
+            let child = CloseFds::builder()
+                .keep_stdio(true)
+                .strategy(result.strategy())
+                .mode(mode)
+                .remap(r, 3000)
+                .remap(w, 3001)
+                .spawn(&mut cmd);
+            // The close_range strategy doesn't support Mode::Close.
+            if let Ok(mut child) = child {
+                assert!(
+                    child.wait().unwrap().success(),
+                    "{} {:?}",
+                    result.strategy(),
+                    mode
+                );
+            }
+        }
+    }
+
+    // Failing in the child process doesn't allocate either.
+    let mut cmd = Command::new(list_fds);
+    let err = CloseFds::builder()
+        .remap(200_000, 3000)
+        .spawn(&mut cmd)
+        .unwrap_err();
+    assert!(CloseFdsError::from_io_error(&err).is_some());
+
+    let sock = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut child = SocketActivation::new()
+        .socket("http", &sock)
+        .spawn(&Program::new("/bin/sh").arg("-c").arg("exit 0"))
+        .unwrap();
+    assert!(child.wait().unwrap().success());
+
+    #[cfg(target_os = "linux")]
+    {
+        let null = std::fs::File::create("/dev/null").unwrap();
+        let mut child = CloseFds::builder()
+            .keep_stdio(true)
+            .remap_fd(&null, 1)
+            .remap(r, 3000)
+            .vfork_spawn(&Program::new(list_fds))
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+    }
+
+    // Dropping a closure in a child process that was forked without Command doesn't free
+    // anything either.
+    for result in probe() {
+        if !result.is_supported() {
+            continue;
+        }
+        let mut func = close_fds_on_exec_with_strategy(vec![0, 1, 2], result.strategy()).unwrap();
+        assert!(
+            fork_and_run(move || {
+                let ok = func().is_ok();
+                drop(func);
+                ok
+            }),
+            "{}",
+            result.strategy()
+        );
+    }
+
+    done.store(true, Ordering::SeqCst);
+    allocator_thread.join().unwrap();
+    close(&[r, w]);
+}
@@ -0,0 +1,129 @@
+use std::{fs, io, os::raw::c_int, path::Path, process::Command, ptr};
+
+mod common;
+
+use closefds::{CloseFds, CloseFdsError};
+use common::{close, fork_and_run, is_open, next_fd, pipe};
+
+// The functions that include/closefds.h declares.
+#[repr(C)]
+struct Prepared {
+    _private: [u8; 0],
+}
+
+extern "C" {
+    fn closefds_prepare(keep_fds: *const c_int, num_keep_fds: usize, mode: c_int) -> *mut Prepared;
+    fn closefds_apply(prepared: *const Prepared) -> c_int;
+    fn closefds_free(prepared: *mut Prepared);
+}
+
+const CLOSEFDS_CLOSE: c_int = 1;
+
+#[test]
+fn c_interface() {
+    let (r, w) = pipe();
+
+    let keep = [0, 1, 2, w];
+    let prepared = unsafe { closefds_prepare(keep.as_ptr(), keep.len(), CLOSEFDS_CLOSE) };
+    assert!(!prepared.is_null());
+    assert!(fork_and_run(|| {
+        let ret = unsafe { closefds_apply(prepared) };
+        ret == 0 && is_open(w) && !is_open(r)
+    }));
+    unsafe { closefds_free(prepared) };
+
+    // Errors that name the step that failed set errno to their plain error number. Opening the
+    // fd directory fails once no file descriptor is left.
+    assert!(fork_and_run(|| unsafe {
+        let next_fd = next_fd();
+        let mut limit = std::mem::zeroed::<libc::rlimit>();
+        libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit);
+        limit.rlim_cur = next_fd as libc::rlim_t;
+        libc::setrlimit(libc::RLIMIT_NOFILE, &limit);
+        closefds_prepare(keep.as_ptr(), keep.len(), CLOSEFDS_CLOSE).is_null()
+            && io::Error::last_os_error().raw_os_error() == Some(libc::EMFILE)
+    }));
+
+    assert_eq!(unsafe { closefds_apply(ptr::null()) }, libc::EINVAL);
+
+    // Invalid modes are rejected.
+    assert!(unsafe { closefds_prepare(ptr::null(), 0, 42) }.is_null());
+    assert_eq!(
+        io::Error::last_os_error().raw_os_error(),
+        Some(libc::EINVAL)
+    );
+
+    close(&[r, w]);
+}
+
+// A C program that includes the header and decodes the error code in its first argument with the
+// macros.
+const HEADER_CHECK: &str = r#"
+#include <closefds.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+/* Conflicting declarations don't compile. The functions aren't linked. */
+closefds_prepared *closefds_prepare(const int *keep_fds, size_t num_keep_fds, int mode);
+int closefds_apply(const closefds_prepared *prepared);
+void closefds_free(closefds_prepared *prepared);
+
+static const int modes[] = {CLOSEFDS_CLOSE_ON_EXEC, CLOSEFDS_CLOSE, CLOSEFDS_CLOSE_ALL};
+
+int main(int argc, char **argv) {
+    int code;
+
+    (void)modes;
+    if (argc != 2) {
+        return 2;
+    }
+    code = atoi(argv[1]);
+    printf("%d %d %d\n", (code & CLOSEFDS_ERROR_BIT) != 0, CLOSEFDS_ERRNO(code),
+           CLOSEFDS_ERROR_FD(code));
+    return 0;
+}
+"#;
+
+#[test]
+fn c_header() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let source = dir.join("closefds_header_check.c");
+    let binary = dir.join("closefds_header_check");
+    fs::write(&source, HEADER_CHECK).unwrap();
+    let status = Command::new(std::env::var_os("CC").unwrap_or_else(|| "cc".into()))
+        .args(["-std=c99", "-Wall", "-Wextra", "-Werror", "-pedantic", "-I"])
+        .arg(Path::new(env!("CARGO_MANIFEST_DIR")).join("include"))
+        .arg("-o")
+        .arg(&binary)
+        .arg(&source)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let decode = |code: c_int| {
+        let output = Command::new(&binary)
+            .arg(code.to_string())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    // An error that names the step that failed: remapping a file descriptor that isn't open.
+    let err = CloseFds::builder()
+        .keep_stdio(true)
+        .remap(200_000, 3000)
+        .spawn(&mut Command::new("/bin/true"))
+        .unwrap_err();
+    let err = CloseFdsError::from_io_error(&err).unwrap();
+    assert_eq!(
+        decode(err.to_raw_os_error()),
+        format!("1 {} 200000\n", libc::EBADF)
+    );
+
+    // Other error codes are plain error numbers.
+    assert_eq!(
+        decode(libc::EBADF),
+        format!("0 {} {}\n", libc::EBADF, libc::EBADF)
+    );
+}
@@ -0,0 +1,55 @@
+//! Helpers shared by the integration tests.
+//!
+//! Tests that change state of the whole process - such as which file descriptor number is opened
+//! next, or what every `fork()` does - live in a test binary of their own, with a single test.
+
+// Every test binary uses a different subset of these.
+#![allow(dead_code)]
+
+use std::os::unix::io::RawFd;
+
+pub fn pipe() -> (RawFd, RawFd) {
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    (fds[0], fds[1])
+}
+
+pub fn close(fds: &[RawFd]) {
+    for &fd in fds {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+}
+
+pub fn is_open(fd: RawFd) -> bool {
+    unsafe { libc::fcntl(fd, libc::F_GETFD) != -1 }
+}
+
+pub fn is_cloexec(fd: RawFd) -> bool {
+    let fd_flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    assert_ne!(fd_flags, -1);
+    fd_flags & libc::FD_CLOEXEC != 0
+}
+
+/// The file descriptor number that the next `open()` returns.
+pub fn next_fd() -> RawFd {
+    let fd = unsafe { libc::fcntl(0, libc::F_DUPFD_CLOEXEC, 0) };
+    assert_ne!(fd, -1);
+    close(&[fd]);
+    fd
+}
+
+/// Run `f` in a child process that is forked without `Command`, and return whether it returned
+/// `true`. `f` must only do what is safe after `fork()`.
+pub fn fork_and_run<F: FnOnce() -> bool>(f: F) -> bool {
+    match unsafe { libc::fork() } {
+        -1 => panic!("fork() failed"),
+        0 => unsafe { libc::_exit(if f() { 0 } else { 1 }) },
+        pid => {
+            let mut status = 0;
+            assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+            libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use closefds::{CloseFds, ForkLock, Program};
+
+// Spawn a child process on another thread with `spawn` while holding a read guard, and check
+// that it only spawns once the guard has been dropped.
+fn check_waits_for_readers<F>(spawn: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let spawned = Arc::new(AtomicBool::new(false));
+    let guard = ForkLock::read();
+    let spawner = {
+        let spawned = spawned.clone();
+        thread::spawn(move || {
+            spawn();
+            spawned.store(true, Ordering::SeqCst);
+        })
+    };
+    thread::sleep(Duration::from_millis(200));
+    assert!(!spawned.load(Ordering::SeqCst));
+    drop(guard);
+    spawner.join().unwrap();
+    assert!(spawned.load(Ordering::SeqCst));
+}
+
+#[test]
+fn spawning_waits_for_readers() {
+    let list_fds = env!("CARGO_BIN_EXE_list_fds");
+
+    // Command with a pre_exec() function uses fork(), which takes the lock in the
+    // pthread_atfork() handlers.
+    check_waits_for_readers(move || {
+        let mut child = CloseFds::builder()
+            .keep_stdio(true)
+            .spawn(Command::new(list_fds).stdout(std::process::Stdio::null()))
+            .unwrap();
+        child.wait().unwrap();
+    });
+
+    check_waits_for_readers(move || {
+        let mut child = CloseFds::builder()
+            .keep_stdio(true)
+            .posix_spawn(&Program::new("/bin/true"))
+            .unwrap();
+        child.wait().unwrap();
+    });
+
+    #[cfg(target_os = "linux")]
+    check_waits_for_readers(move || {
+        let mut child = CloseFds::builder()
+            .keep_stdio(true)
+            .vfork_spawn(&Program::new("/bin/true"))
+            .unwrap();
+        child.wait().unwrap();
+    });
+
+    // A waiting writer keeps new readers out, so readers whose guards always overlap don't
+    // starve it.
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    {
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let done = done.clone();
+                thread::spawn(move || {
+                    while !done.load(Ordering::SeqCst) {
+                        let _guard = ForkLock::read();
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                })
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(50));
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let spawner = thread::spawn(move || {
+            let mut child = CloseFds::builder()
+                .keep_stdio(true)
+                .spawn(&mut Command::new("/bin/true"))
+                .unwrap();
+            child.wait().unwrap();
+            sender.send(()).unwrap();
+        });
+        let spawned = receiver.recv_timeout(Duration::from_secs(10));
+        done.store(true, Ordering::SeqCst);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        spawner.join().unwrap();
+        assert!(spawned.is_ok(), "spawning was starved by readers");
+    }
+
+    // A thread that holds a guard itself can still spawn child processes.
+    let _read = ForkLock::read();
+    let _write = ForkLock::write();
+    let mut child = CloseFds::builder()
+        .keep_stdio(true)
+        .spawn(&mut Command::new("/bin/true"))
+        .unwrap();
+    assert!(child.wait().unwrap().success());
+}
@@ -0,0 +1,39 @@
+use std::process::{Command, Stdio};
+
+mod common;
+
+use closefds::{probe, CloseFds, Strategy};
+use common::{close, next_fd, pipe};
+
+#[test]
+fn remap_onto_next_free_fd() {
+    let (r, w) = pipe();
+
+    let strategies = probe()
+        .into_iter()
+        .filter(|result| result.is_supported())
+        .map(|result| result.strategy())
+        .chain(Some(Strategy::Auto));
+    for strategy in strategies {
+        // Strategies that read the fd directory with readdir() open it when the plan is built,
+        // and it must not end up at the number that the pipe is remapped to.
+        let to = next_fd();
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_list_fds"));
+        let child = CloseFds::builder()
+            .keep_stdio(true)
+            .strategy(strategy)
+            .remap(w, to)
+            .spawn(cmd.stdout(Stdio::piped()))
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "{}", strategy);
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            format!("0\n1\n2\n{}\n", to),
+            "{}",
+            strategy
+        );
+    }
+
+    close(&[r, w]);
+}
@@ -0,0 +1,24 @@
+mod common;
+
+use closefds::set_cloexec_on_all_except;
+use common::{close, is_cloexec, pipe};
+
+#[test]
+fn set_cloexec_on_inherited_fds() {
+    let (r, w) = pipe();
+    let cloexec_fd = unsafe { libc::fcntl(w, libc::F_DUPFD_CLOEXEC, 0) };
+    assert_ne!(cloexec_fd, -1);
+
+    let changed = set_cloexec_on_all_except(&[0, 1, 2, w]).unwrap();
+    assert!(changed.contains(&r), "{:?}", changed);
+    assert!(!changed.contains(&w));
+    assert!(!changed.contains(&cloexec_fd));
+    assert!(changed.iter().all(|&fd| fd > 2));
+    assert!(is_cloexec(r));
+    assert!(!is_cloexec(w));
+
+    // Nothing is left to change.
+    assert_eq!(set_cloexec_on_all_except(&[0, 1, 2, w]).unwrap(), vec![]);
+
+    close(&[r, w, cloexec_fd]);
+}
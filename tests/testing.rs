@@ -0,0 +1,49 @@
+#![cfg(feature = "testing")]
+
+use std::{os::unix::process::CommandExt, panic, process::Command};
+
+mod common;
+
+use closefds::{
+    close_fds_on_exec,
+    testing::{assert_no_new_fds, probe_builder, probe_command, FdSnapshot},
+    CloseFds,
+};
+use common::{close, pipe};
+
+#[test]
+fn snapshots_and_probes() {
+    let before = FdSnapshot::take().unwrap();
+    let (r, w) = pipe();
+    let after = FdSnapshot::take().unwrap();
+    assert_eq!(after.new_since(&before), vec![r, w]);
+    assert!(after.fds().contains(&0));
+
+    let contents = assert_no_new_fds(|| std::fs::read("Cargo.toml").unwrap());
+    assert!(!contents.is_empty());
+    let leaked = panic::catch_unwind(|| assert_no_new_fds(pipe)).unwrap_err();
+    let message = leaked.downcast_ref::<String>().unwrap();
+    assert!(message.contains("new file descriptors"), "{}", message);
+
+    // The probe child inherits everything without FD_CLOEXEC, and nothing else.
+    let cloexec_fd = unsafe { libc::fcntl(w, libc::F_DUPFD_CLOEXEC, 0) };
+    let mut cmd = Command::new("/nonexistent/program");
+    let fds = probe_command(&mut cmd).unwrap();
+    assert!(fds.contains(&r) && fds.contains(&w));
+    assert!(!fds.contains(&cloexec_fd));
+
+    let mut cmd = Command::new("/nonexistent/program");
+    unsafe {
+        cmd.pre_exec(close_fds_on_exec(vec![0, 1, 2, w]).unwrap());
+    }
+    let fds = probe_command(&mut cmd).unwrap();
+    assert!(fds.iter().all(|&fd| fd <= 2 || fd == w), "{:?}", fds);
+    assert!(fds.contains(&w));
+
+    let builder = CloseFds::builder().keep_stdio(true).remap(r, 3000);
+    let fds = probe_builder(builder, &mut Command::new("/nonexistent/program")).unwrap();
+    assert!(fds.iter().all(|&fd| fd <= 2 || fd == 3000), "{:?}", fds);
+    assert!(fds.contains(&3000));
+
+    close(&[r, w, cloexec_fd]);
+}
@@ -2,36 +2,76 @@ use std::{
     fs::File,
     io::{self, Read},
     os::unix::{
-        io::{FromRawFd, RawFd},
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
         process::CommandExt,
     },
-    process::Command,
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use closefds::close_fds_on_exec;
+mod common;
+
+use closefds::{
+    audit, close_fds_from, close_fds_on_exec, close_fds_on_exec_with_strategy, probe, CloseFds,
+    CloseFdsError, CommandExt as _, FdKind, Mode, Phase, Prepared, Program, Receiver,
+    SocketActivation, Strategy, MANIFEST_VAR,
+};
+use common::{close, fork_and_run, is_open, pipe};
 
-fn pipe() -> io::Result<(RawFd, RawFd)> {
-    let mut fds = [0; 2];
-    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
-        return Err(io::Error::last_os_error());
+fn child_fds<F>(close_func: F) -> Vec<RawFd>
+where
+    F: FnMut() -> io::Result<()> + Send + Sync + 'static,
+{
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_list_fds"));
+    unsafe {
+        cmd.pre_exec(close_func);
     }
-    Ok((fds[0], fds[1]))
+    let output = cmd.output().unwrap();
+    assert!(output.status.success());
+
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| line.parse().unwrap())
+        .collect()
+}
+
+/// The supported strategies that walk the fd directory instead of using `close_range()`.
+fn walking_strategies() -> Vec<Strategy> {
+    probe()
+        .into_iter()
+        .filter(|result| result.is_supported())
+        .map(|result| result.strategy())
+        .filter(|&strategy| {
+            matches!(
+                strategy,
+                Strategy::Getdents | Strategy::ProcfsReaddir | Strategy::DevFd
+            )
+        })
+        .collect()
 }
 
 #[test]
 fn run_test() {
-    let (r1, w1) = pipe().unwrap();
+    let (r1, w1) = pipe();
 
     let close_func = close_fds_on_exec(vec![0, 1, 2, w1]).unwrap();
 
-    let (r2, w2) = pipe().unwrap();
+    let (r2, w2) = pipe();
 
-    let mut cmd = Command::new("target/debug/test_prog");
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_test_prog"));
     cmd.arg(format!("{}", w1));
     cmd.arg(format!("{}", r1));
     cmd.arg(format!("{}", w2));
     cmd.arg(format!("{}", r2));
-    cmd.before_exec(close_func);
+    unsafe {
+        cmd.pre_exec(close_func);
+    }
     let mut spawn = cmd.spawn().unwrap();
 
     unsafe {
@@ -50,3 +90,897 @@ fn run_test() {
 
     assert!(status.success());
 }
+
+#[test]
+fn parent_closing_fds_while_spawning() {
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let churn = {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                let (r, w) = pipe();
+                unsafe {
+                    libc::close(r);
+                    libc::close(w);
+                }
+            }
+        })
+    };
+
+    // Auto usually uses close_range(), so also walk the fd directory of the child process with
+    // every strategy that does.
+    for strategy in Some(Strategy::Auto).into_iter().chain(walking_strategies()) {
+        for _ in 0..100 {
+            let close_func = close_fds_on_exec_with_strategy(vec![0, 1, 2], strategy).unwrap();
+            assert_eq!(child_fds(close_func), vec![0, 1, 2], "{}", strategy);
+        }
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    churn.join().unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn spawn_from_thread_with_unshared_fd_table() {
+    // After unshare(), this only exists in the fd table of the main thread.
+    let (main_r, main_w) = pipe();
+    let main_only = unsafe { libc::fcntl(main_r, libc::F_DUPFD_CLOEXEC, 4800) };
+    assert_eq!(main_only, 4800);
+
+    thread::spawn(move || {
+        assert_eq!(unsafe { libc::unshare(libc::CLONE_FILES) }, 0);
+        close(&[main_only]);
+
+        // These only exist in this thread's fd table.
+        let (r, w) = pipe();
+        let thread_only = unsafe { libc::fcntl(r, libc::F_DUPFD_CLOEXEC, 4801) };
+        assert_eq!(thread_only, 4801);
+
+        // The fd directory is walked in this process by audit(), which always uses getdents64()
+        // on Linux. After fork(), /proc/self would refer to the child process either way.
+        let fds: Vec<RawFd> = audit().unwrap().iter().map(|info| info.fd()).collect();
+        assert!(fds.contains(&thread_only), "{:?}", fds);
+        assert!(!fds.contains(&main_only), "{:?}", fds);
+
+        let close_func = close_fds_on_exec(vec![0, 1, 2, w]).unwrap();
+        assert_eq!(child_fds(close_func), vec![0, 1, 2, w]);
+
+        close(&[r, w, thread_only]);
+    })
+    .join()
+    .unwrap();
+
+    close(&[main_r, main_w, main_only]);
+}
+
+#[test]
+fn concurrent_spawns_reusing_closures() {
+    // Some file descriptors without FD_CLOEXEC that the children must not
+    // inherit.
+    let pipes: Vec<(RawFd, RawFd)> = (0..16).map(|_| pipe()).collect();
+
+    // The children walk the fd directory at the same time, with the directories that were
+    // opened when the closures were built.
+    for strategy in walking_strategies() {
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                thread::spawn(move || {
+                    let mut cmd = Command::new(env!("CARGO_BIN_EXE_list_fds"));
+                    unsafe {
+                        cmd.pre_exec(
+                            CloseFds::builder()
+                                .keep_stdio(true)
+                                .strategy(strategy)
+                                .build()
+                                .unwrap(),
+                        );
+                    }
+
+                    // Every spawn reuses the same closure.
+                    for _ in 0..25 {
+                        let output = cmd.output().unwrap();
+                        assert!(output.status.success(), "{}", strategy);
+                        assert_eq!(output.stdout.as_slice(), b"0\n1\n2\n", "{}", strategy);
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    for (r, w) in pipes {
+        unsafe {
+            libc::close(r);
+            libc::close(w);
+        }
+    }
+}
+
+#[test]
+fn each_supported_strategy() {
+    let (r, w) = pipe();
+
+    let results = probe();
+    if cfg!(target_os = "linux") {
+        let getdents = results
+            .iter()
+            .find(|result| result.strategy() == Strategy::Getdents)
+            .unwrap();
+        assert!(getdents.is_supported(), "{:?}", getdents.error());
+    }
+
+    for result in results.iter().filter(|result| result.is_supported()) {
+        let close_func =
+            close_fds_on_exec_with_strategy(vec![0, 1, 2, w], result.strategy()).unwrap();
+        assert_eq!(
+            child_fds(close_func),
+            vec![0, 1, 2, w],
+            "{}",
+            result.strategy()
+        );
+    }
+
+    unsafe {
+        libc::close(r);
+        libc::close(w);
+    }
+}
+
+#[test]
+fn builder() {
+    // Use file descriptor numbers that no other test is going to use, with
+    // open file descriptors both inside and outside of the kept range.
+    let (r, w) = pipe();
+    for &fd in &[1000, 1001, 1003] {
+        assert_eq!(unsafe { libc::dup2(w, fd) }, fd);
+    }
+
+    let close_func = CloseFds::builder()
+        .keep_stdio(true)
+        .keep_range(1000..1003)
+        .build()
+        .unwrap();
+    assert_eq!(child_fds(close_func), vec![0, 1, 2, 1000, 1001]);
+
+    for &fd in &[r, w, 1000, 1001, 1003] {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+}
+
+#[test]
+fn close_mode() {
+    let (r, w) = pipe();
+
+    for result in probe().iter().filter(|result| result.is_supported()) {
+        if result.strategy() == Strategy::CloseRange {
+            continue;
+        }
+        let close_func = CloseFds::builder()
+            .keep_stdio(true)
+            .strategy(result.strategy())
+            .mode(Mode::Close)
+            .build()
+            .unwrap();
+        assert_eq!(
+            child_fds(close_func),
+            vec![0, 1, 2],
+            "{}",
+            result.strategy()
+        );
+    }
+
+    // The pipe that Command uses to report exec() errors must survive.
+    let mut cmd = Command::new("/nonexistent/program");
+    unsafe {
+        cmd.pre_exec(
+            CloseFds::builder()
+                .keep_stdio(true)
+                .mode(Mode::Close)
+                .build()
+                .unwrap(),
+        );
+    }
+    assert_eq!(cmd.spawn().unwrap_err().kind(), io::ErrorKind::NotFound);
+
+    unsafe {
+        libc::close(r);
+        libc::close(w);
+    }
+}
+
+#[test]
+fn close_all_mode_without_exec() {
+    let (r, w) = pipe();
+    let cloexec_fd = unsafe { libc::fcntl(r, libc::F_DUPFD_CLOEXEC, 0) };
+    assert_ne!(cloexec_fd, -1);
+
+    let mut close_func = CloseFds::builder()
+        .keep(w)
+        .mode(Mode::CloseAll)
+        .build()
+        .unwrap();
+
+    let pid = unsafe { libc::fork() };
+    assert_ne!(pid, -1);
+    if pid == 0 {
+        unsafe {
+            let ok = close_func().is_ok()
+                && libc::fcntl(r, libc::F_GETFD) == -1
+                && libc::fcntl(cloexec_fd, libc::F_GETFD) == -1
+                && libc::fcntl(0, libc::F_GETFD) == -1;
+            let byte = [ok as u8];
+            libc::write(w, byte.as_ptr() as *const libc::c_void, 1);
+            libc::_exit(0);
+        }
+    }
+
+    unsafe {
+        libc::close(w);
+        libc::close(cloexec_fd);
+    }
+    let mut buf = vec![];
+    let mut f = unsafe { File::from_raw_fd(r) };
+    f.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, vec![1]);
+
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+}
+
+#[test]
+fn keep_unbounded_ranges() {
+    let (r, w) = pipe();
+    for &fd in &[2000, 2001, 2005] {
+        assert_eq!(unsafe { libc::dup2(w, fd) }, fd);
+    }
+    // Kept ranges don't clear FD_CLOEXEC.
+    assert_eq!(unsafe { libc::fcntl(w, libc::F_DUPFD_CLOEXEC, 2002) }, 2002);
+
+    // Other tests create file descriptors concurrently, including some at fixed numbers above
+    // these, so only look at the ones that this test created.
+    let high_fds = |fds: Vec<RawFd>| -> Vec<RawFd> {
+        fds.into_iter()
+            .filter(|fd| (2000..=2005).contains(fd))
+            .collect()
+    };
+
+    let close_func = close_fds_from(2001).unwrap();
+    assert_eq!(high_fds(child_fds(close_func)), vec![2000]);
+
+    let close_func = CloseFds::builder()
+        .keep_stdio(true)
+        .keep_range(2001..)
+        .build()
+        .unwrap();
+    let fds = child_fds(close_func);
+    assert_eq!(fds[..3], [0, 1, 2]);
+    assert_eq!(high_fds(fds), vec![2001, 2005]);
+
+    for result in probe().iter().filter(|result| result.is_supported()) {
+        let close_func = CloseFds::builder()
+            .keep_range(..=2001)
+            .strategy(result.strategy())
+            .build()
+            .unwrap();
+        assert_eq!(
+            high_fds(child_fds(close_func)),
+            vec![2000, 2001],
+            "{}",
+            result.strategy()
+        );
+    }
+
+    close(&[r, w, 2000, 2001, 2002, 2005]);
+}
+
+#[test]
+fn keep_ranges_with_long_running_child() {
+    // If the pipe that Command uses to report exec() errors was inherited, spawn() would only
+    // return once the child process exits.
+    let spawn_and_kill = |spawn: &dyn Fn(&mut Command) -> io::Result<Child>| {
+        let mut cmd = Command::new("sleep");
+        cmd.arg("10");
+        let start = Instant::now();
+        let mut child = spawn(&mut cmd).unwrap();
+        let elapsed = start.elapsed();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+    };
+
+    spawn_and_kill(&|cmd| {
+        unsafe {
+            cmd.pre_exec(close_fds_from(10).unwrap());
+        }
+        cmd.spawn()
+    });
+    spawn_and_kill(&|cmd| CloseFds::builder().keep_range(3..).spawn(cmd));
+    spawn_and_kill(&|cmd| CloseFds::builder().keep_range(..).spawn(cmd));
+}
+
+#[test]
+fn keep_borrowed_fds() {
+    let (r, w) = pipe();
+    let w = unsafe { OwnedFd::from_raw_fd(w) };
+    let kept = w.as_raw_fd();
+
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_list_fds"));
+    let child = CloseFds::builder()
+        .keep_stdio(true)
+        .keep_fd(&w)
+        .spawn(cmd.stdout(Stdio::piped()))
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("0\n1\n2\n{}\n", kept)
+    );
+
+    // Spawning the Command again once the file descriptor is gone fails instead of running
+    // without the plan.
+    drop(w);
+    assert_eq!(cmd.spawn().unwrap_err().raw_os_error(), Some(libc::EBADF));
+
+    close(&[r]);
+}
+
+#[test]
+fn spawning_uses_up_command() {
+    let check = |spawn: &dyn Fn(&mut Command) -> io::Result<()>| {
+        let list_fds = env!("CARGO_BIN_EXE_list_fds");
+        let mut cmd = Command::new(list_fds);
+        cmd.stdout(Stdio::null());
+        spawn(&mut cmd).unwrap();
+
+        // The closure that stays installed refuses to spawn the Command again, with or without
+        // a new plan.
+        let err = spawn(&mut cmd).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+        let err = cmd.spawn().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+
+        // A new Command restarts the process.
+        spawn(Command::new(list_fds).stdout(Stdio::null())).unwrap();
+    };
+    check(&|cmd| cmd.close_fds().status().map(drop));
+    check(&|cmd| cmd.close_fds().output().map(drop));
+    check(&|cmd| {
+        CloseFds::builder()
+            .keep_stdio(true)
+            .spawn(cmd)?
+            .wait()
+            .map(drop)
+    });
+}
+
+#[test]
+fn remap_fds() {
+    let (r1, w1) = pipe();
+    let (r2, w2) = pipe();
+    assert_eq!(unsafe { libc::dup2(w1, 3000) }, 3000);
+    assert_eq!(unsafe { libc::dup2(w2, 3001) }, 3001);
+
+    let swap = || {
+        CloseFds::builder()
+            .keep_stdio(true)
+            .remap(3000, 3001)
+            .remap(3001, 3000)
+            .remap(w1, 3005)
+    };
+
+    assert_eq!(
+        child_fds(swap().build().unwrap()),
+        vec![0, 1, 2, 3000, 3001, 3005]
+    );
+
+    let mut close_func = swap().mode(Mode::CloseAll).build().unwrap();
+    let pid = unsafe { libc::fork() };
+    assert_ne!(pid, -1);
+    if pid == 0 {
+        unsafe {
+            if close_func().is_ok() {
+                libc::write(3000, b"2".as_ptr() as *const libc::c_void, 1);
+                libc::write(3001, b"1".as_ptr() as *const libc::c_void, 1);
+                libc::write(3005, b"1".as_ptr() as *const libc::c_void, 1);
+            }
+            libc::_exit(0);
+        }
+    }
+
+    for &fd in &[w1, w2, 3000, 3001] {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+    let mut buf = String::new();
+    let mut f = unsafe { File::from_raw_fd(r1) };
+    f.read_to_string(&mut buf).unwrap();
+    assert_eq!(buf, "11");
+    buf.clear();
+    let mut f = unsafe { File::from_raw_fd(r2) };
+    f.read_to_string(&mut buf).unwrap();
+    assert_eq!(buf, "2");
+
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+
+    let err = CloseFds::builder()
+        .remap(3000, 3)
+        .remap(3001, 3)
+        .build()
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn socket_activation() {
+    let (r1, w1) = pipe();
+    let (r2, w2) = pipe();
+    let w1 = unsafe { OwnedFd::from_raw_fd(w1) };
+    let w2 = unsafe { OwnedFd::from_raw_fd(w2) };
+
+    // Closes the write end of the child's stdout and reads the rest.
+    let stdout = |r: RawFd, w: RawFd| {
+        close(&[w]);
+        let mut stdout = String::new();
+        unsafe { File::from_raw_fd(r) }
+            .read_to_string(&mut stdout)
+            .unwrap();
+        stdout
+    };
+
+    let (r, w) = pipe();
+    let program = Program::new("/bin/sh")
+        .arg("-c")
+        .arg(concat!(
+            "echo $$ $LISTEN_PID $LISTEN_FDS $LISTEN_FDNAMES $EXTRA; exec ",
+            env!("CARGO_BIN_EXE_list_fds")
+        ))
+        .env("EXTRA", "extra");
+    let mut child =
+        SocketActivation::with_builder(CloseFds::builder().keep_stdio(true).remap(w, 1))
+            .socket("first", &w2)
+            .socket("second", &w1)
+            .spawn(&program)
+            .unwrap();
+    let pid = child.id();
+    assert_eq!(
+        stdout(r, w),
+        format!("{} {} 2 first:second extra\n0\n1\n2\n3\n4\n", pid, pid)
+    );
+    assert!(child.wait().unwrap().success());
+
+    // The environment is exactly the one of the Program.
+    let (r, w) = pipe();
+    let program = Program::new("/bin/sh")
+        .arg("-c")
+        .arg("echo ${HOME-unset} $LISTEN_FDS; pwd")
+        .env_clear()
+        .current_dir("/");
+    let mut child =
+        SocketActivation::with_builder(CloseFds::builder().keep_stdio(true).remap(w, 1))
+            .socket("only", &w1)
+            .spawn(&program)
+            .unwrap();
+    assert_eq!(stdout(r, w), "unset 1\n/\n");
+    assert!(child.wait().unwrap().success());
+
+    let err = SocketActivation::new()
+        .socket("a:b", &w1)
+        .spawn(&Program::new("/bin/true"))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    unsafe {
+        libc::close(r1);
+        libc::close(r2);
+    }
+}
+
+#[test]
+fn receive_fds() {
+    let (r, w) = pipe();
+    let w = unsafe { OwnedFd::from_raw_fd(w) };
+
+    let output = |child: std::process::Child| {
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let (stdout_r, stdout_w) = pipe();
+    let mut child =
+        SocketActivation::with_builder(CloseFds::builder().keep_stdio(true).remap(stdout_w, 1))
+            .socket("a", &w)
+            .socket("b", &w)
+            .spawn(&Program::new(env!("CARGO_BIN_EXE_receive_fds")).arg("listen"))
+            .unwrap();
+    close(&[stdout_w]);
+    let mut stdout = String::new();
+    unsafe { File::from_raw_fd(stdout_r) }
+        .read_to_string(&mut stdout)
+        .unwrap();
+    assert!(child.wait().unwrap().success());
+    assert_eq!(stdout, "a=3 true\nb=4 true\n");
+
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_receive_fds"));
+    cmd.env(
+        MANIFEST_VAR,
+        Receiver::manifest_value(vec![("log", 3000)]).unwrap(),
+    )
+    .stdout(Stdio::piped());
+    let child = CloseFds::builder()
+        .keep_stdio(true)
+        .remap_fd(&w, 3000)
+        .spawn(&mut cmd)
+        .unwrap();
+    assert_eq!(output(child), "log=3000 true\n");
+
+    // Nothing is open at the advertised file descriptor.
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_receive_fds"));
+    cmd.env(MANIFEST_VAR, "log=3000").stdout(Stdio::piped());
+    let child = CloseFds::builder()
+        .keep_stdio(true)
+        .spawn(&mut cmd)
+        .unwrap();
+    assert!(output(child).starts_with("error: inherited file descriptor 3000 isn't open"));
+
+    // A malformed count is rejected before anything is collected.
+    for count in &["2147483647", "2147483645"] {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_receive_fds"));
+        cmd.arg("listen")
+            .env("LISTEN_FDS", count)
+            .stdout(Stdio::piped());
+        let child = CloseFds::builder()
+            .keep_stdio(true)
+            .spawn(&mut cmd)
+            .unwrap();
+        assert!(output(child).starts_with("error: invalid inherited file descriptor entry"));
+    }
+
+    unsafe {
+        libc::close(r);
+    }
+}
+
+#[test]
+fn command_ext() {
+    let (r, w) = pipe();
+    let w = unsafe { OwnedFd::from_raw_fd(w) };
+    let kept = w.as_raw_fd();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_list_fds"))
+        .close_fds()
+        .inherit_fd(&w)
+        .inherit_fd_as(&w, 3002)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("0\n1\n2\n{}\n3002\n", kept)
+    );
+
+    let status = Command::new(env!("CARGO_BIN_EXE_list_fds"))
+        .stdout(Stdio::null())
+        .close_fds()
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let err = Command::new(env!("CARGO_BIN_EXE_list_fds"))
+        .close_fds()
+        .mode(Mode::CloseAll)
+        .spawn()
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    unsafe {
+        libc::close(r);
+    }
+}
+
+#[test]
+fn posix_spawn() {
+    let stdout_of = |builder: closefds::CloseFdsBuilder, program: &Program| {
+        let (r, w) = pipe();
+        let mut child = builder.remap(w, 1).posix_spawn(program).unwrap();
+        unsafe {
+            libc::close(w);
+        }
+        let mut stdout = String::new();
+        unsafe { File::from_raw_fd(r) }
+            .read_to_string(&mut stdout)
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        (child.is_posix_spawn(), stdout)
+    };
+    let list_fds = |builder| stdout_of(builder, &Program::new(env!("CARGO_BIN_EXE_list_fds")));
+
+    let (r, w) = pipe();
+    let cloexec_fd = unsafe { libc::fcntl(w, libc::F_DUPFD_CLOEXEC, 0) };
+    assert_ne!(cloexec_fd, -1);
+
+    // Moving the file descriptors to 3 and 4 keeps a single range, so this
+    // can use posix_spawn() where it supports closefrom.
+    let (_, stdout) = list_fds(
+        CloseFds::builder()
+            .keep_range(0..=2)
+            .remap(w, 4)
+            .remap(cloexec_fd, 3),
+    );
+    assert_eq!(stdout, "0\n1\n2\n3\n4\n");
+
+    // A gap below the highest kept file descriptor needs the fallback.
+    let (used_posix_spawn, stdout) = list_fds(CloseFds::builder().keep_stdio(true).keep(4000));
+    assert!(!used_posix_spawn);
+    assert_eq!(stdout, "0\n1\n2\n");
+
+    if cfg!(all(target_os = "linux", target_env = "gnu")) {
+        let (used_posix_spawn, _) = list_fds(CloseFds::builder().keep_stdio(true));
+        assert!(used_posix_spawn);
+    }
+
+    // File descriptors with FD_CLOEXEC in a kept range aren't inherited, and individually kept
+    // ones are. Other tests may leak file descriptors below 4500 into the range.
+    assert_eq!(unsafe { libc::fcntl(w, libc::F_DUPFD_CLOEXEC, 4500) }, 4500);
+    assert_eq!(unsafe { libc::dup2(w, 4501) }, 4501);
+    assert_eq!(unsafe { libc::fcntl(w, libc::F_DUPFD_CLOEXEC, 4502) }, 4502);
+    let (_, stdout) = list_fds(CloseFds::builder().keep_range(0..=4501).keep(4502));
+    let high_fds: Vec<&str> = stdout
+        .lines()
+        .filter(|line| line.parse::<RawFd>().unwrap() >= 4500)
+        .collect();
+    assert_eq!(high_fds, vec!["4501", "4502"]);
+
+    // The program is looked up in the PATH of the child process, and its environment is exactly
+    // the one of the Program.
+    let bin_dir = std::path::Path::new(env!("CARGO_BIN_EXE_list_fds"))
+        .parent()
+        .unwrap()
+        .to_owned();
+    let (_, stdout) = list_fds(CloseFds::builder().keep_stdio(true));
+    let (_, from_path) = stdout_of(
+        CloseFds::builder().keep_stdio(true),
+        &Program::new("list_fds")
+            .env_clear()
+            .env("PATH", format!("/nonexistent:{}", bin_dir.display())),
+    );
+    assert_eq!(from_path, stdout);
+    for builder in [
+        CloseFds::builder().keep_stdio(true),
+        CloseFds::builder().keep_stdio(true).keep(4000),
+    ] {
+        let (_, stdout) = stdout_of(
+            builder,
+            &Program::new("sh")
+                .args(["-c", "echo ${HOME-unset} $FOO $PWD"])
+                .env_clear()
+                .env("PATH", "/bin:/usr/bin")
+                .env("FOO", "foo")
+                .current_dir("/"),
+        );
+        assert_eq!(stdout, "unset foo /\n");
+    }
+
+    let err = CloseFds::builder()
+        .keep_stdio(true)
+        .posix_spawn(&Program::new("list_fds").env_clear())
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+    for &fd in &[r, w, cloexec_fd, 4500, 4501, 4502] {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn vfork_spawn() {
+    let (r, w) = pipe();
+    let (r2, w2) = pipe();
+
+    // The program is looked up in the PATH of the child process.
+    let program = Program::new("list_fds").env_clear().env(
+        "PATH",
+        format!(
+            "/nonexistent:{}",
+            std::path::Path::new(env!("CARGO_BIN_EXE_list_fds"))
+                .parent()
+                .unwrap()
+                .display()
+        ),
+    );
+    let mut child = CloseFds::builder()
+        .keep_stdio(true)
+        .keep(w2)
+        .remap(w, 1)
+        .vfork_spawn(&program)
+        .unwrap();
+    unsafe {
+        libc::close(w);
+    }
+    let mut stdout = String::new();
+    unsafe { File::from_raw_fd(r) }
+        .read_to_string(&mut stdout)
+        .unwrap();
+    assert!(child.wait().unwrap().success());
+    assert_eq!(stdout, format!("0\n1\n2\n{}\n", w2));
+
+    // The environment of the child process is exactly the one of the Program.
+    let (r, w) = pipe();
+    let mut child = CloseFds::builder()
+        .keep_stdio(true)
+        .remap(w, 1)
+        .vfork_spawn(
+            &Program::new("sh")
+                .args(["-c", "echo ${HOME-unset} $FOO $PWD"])
+                .env_clear()
+                .env("PATH", "/bin:/usr/bin")
+                .env("FOO", "foo")
+                .current_dir("/"),
+        )
+        .unwrap();
+    unsafe {
+        libc::close(w);
+    }
+    let mut stdout = String::new();
+    unsafe { File::from_raw_fd(r) }
+        .read_to_string(&mut stdout)
+        .unwrap();
+    assert!(child.wait().unwrap().success());
+    assert_eq!(stdout, "unset foo /\n");
+
+    let err = CloseFds::builder()
+        .vfork_spawn(&Program::new("/nonexistent/program"))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+    unsafe {
+        libc::close(r2);
+        libc::close(w2);
+    }
+}
+
+#[test]
+fn audit_fds() {
+    let (r, w) = pipe();
+    let cloexec_fd = unsafe { libc::fcntl(w, libc::F_DUPFD_CLOEXEC, 0) };
+    assert_ne!(cloexec_fd, -1);
+
+    let fds = audit().unwrap();
+    let find = |fd: RawFd| fds.iter().find(|info| info.fd() == fd).unwrap();
+    assert_eq!(find(r).kind(), &FdKind::Pipe);
+    assert!(!find(r).is_cloexec());
+    assert!(find(cloexec_fd).is_cloexec());
+    assert_eq!(find(w).flags().unwrap() & libc::O_ACCMODE, libc::O_WRONLY);
+
+    #[cfg(target_os = "linux")]
+    {
+        let event_fd = unsafe { libc::eventfd(0, 0) };
+        let mem_fd = unsafe { libc::memfd_create(b"audit\0".as_ptr() as *const _, 0) };
+        let epoll_fd = unsafe { libc::epoll_create1(0) };
+
+        let fds = audit().unwrap();
+        let find = |fd: RawFd| fds.iter().find(|info| info.fd() == fd).unwrap();
+        assert_eq!(find(event_fd).kind(), &FdKind::EventFd);
+        assert_eq!(find(mem_fd).kind(), &FdKind::MemFd);
+        assert_eq!(
+            find(epoll_fd).kind(),
+            &FdKind::AnonInode("[eventpoll]".to_owned())
+        );
+        assert!(find(r)
+            .target()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("pipe:"));
+        assert_ne!(find(cloexec_fd).flags().unwrap() & libc::O_CLOEXEC, 0);
+
+        for &fd in &[event_fd, mem_fd, epoll_fd] {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+    }
+
+    for &fd in &[r, w, cloexec_fd] {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+}
+
+// A thread with a file descriptor table of its own sees the details of its own file descriptors.
+#[cfg(target_os = "linux")]
+#[test]
+fn audit_unshared_fds() {
+    std::thread::spawn(|| {
+        assert_eq!(unsafe { libc::unshare(libc::CLONE_FILES) }, 0);
+        // Only the file descriptor table of this thread has this file descriptor.
+        let null = File::open("/dev/null").unwrap();
+        let fd = unsafe { libc::fcntl(null.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 4700) };
+        assert_eq!(fd, 4700);
+
+        let fds = audit().unwrap();
+        let info = fds.iter().find(|info| info.fd() == fd).unwrap();
+        assert_eq!(info.target(), Some(std::path::Path::new("/dev/null")));
+        assert_ne!(info.flags().unwrap() & libc::O_CLOEXEC, 0);
+        unsafe {
+            libc::close(fd);
+        }
+    })
+    .join()
+    .unwrap();
+}
+
+#[test]
+fn child_errors() {
+    // Nothing is open at this file descriptor, so remapping it fails in the child process.
+    const NOT_OPEN: RawFd = 200_000;
+
+    // The error code makes it through the exec-error pipe of Command.
+    let close_fds = CloseFds::builder().remap(NOT_OPEN, 3000).build().unwrap();
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_list_fds"));
+    unsafe {
+        cmd.pre_exec(close_fds);
+    }
+    let err = cmd.spawn().unwrap_err();
+    let close_fds_err = CloseFdsError::from_io_error(&err).unwrap();
+    assert_eq!(close_fds_err.phase(), Phase::Remap);
+    assert_eq!(close_fds_err.errno(), libc::EBADF);
+    assert_eq!(close_fds_err.fd(), Some(NOT_OPEN));
+    assert_eq!(
+        CloseFdsError::from_raw_os_error(close_fds_err.to_raw_os_error()),
+        Some(close_fds_err)
+    );
+
+    // spawn() decodes it.
+    let err = CloseFds::builder()
+        .remap(NOT_OPEN, 3000)
+        .spawn(&mut Command::new(env!("CARGO_BIN_EXE_list_fds")))
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), None);
+    assert_eq!(CloseFdsError::from_io_error(&err), Some(close_fds_err));
+    assert!(err.to_string().contains("fd 200000"), "{}", err);
+
+    assert_eq!(CloseFdsError::from_raw_os_error(libc::EBADF), None);
+}
+
+#[test]
+fn prepared_for_manual_fork() {
+    let (r, w) = pipe();
+    let prepared = Prepared::new(
+        CloseFds::builder()
+            .keep_stdio(true)
+            .remap(w, 3000)
+            .mode(Mode::CloseAll),
+    )
+    .unwrap();
+    assert!(fork_and_run(|| {
+        unsafe { prepared.apply_in_child() }.is_ok() && is_open(3000) && !is_open(r)
+    }));
+    drop(prepared);
+
+    let prepared = Prepared::new(CloseFds::builder().remap(200_000, 3000)).unwrap();
+    assert!(fork_and_run(|| {
+        match unsafe { prepared.apply_in_child() } {
+            Err(errno::Errno(code)) => CloseFdsError::from_raw_os_error(code)
+                .is_some_and(|err| err.phase() == Phase::Remap && err.errno() == libc::EBADF),
+            Ok(()) => false,
+        }
+    }));
+
+    close(&[r, w]);
+}
@@ -0,0 +1,98 @@
+#![cfg(feature = "tokio")]
+
+use std::{
+    io,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    process::Stdio,
+};
+
+mod common;
+
+use closefds::CommandExt;
+use common::is_open;
+use tokio::{
+    net::UnixStream,
+    process::Command,
+    runtime,
+    signal::unix::{signal, SignalKind},
+};
+
+// Like list_fds, but from the parent's point of view.
+fn open_fds() -> Vec<RawFd> {
+    (0..1024).filter(|&fd| is_open(fd)).collect()
+}
+
+fn parse_fds(stdout: Vec<u8>) -> Vec<RawFd> {
+    String::from_utf8(stdout)
+        .unwrap()
+        .lines()
+        .map(|line| line.parse().unwrap())
+        .collect()
+}
+
+#[test]
+fn runtime_fds_are_not_inherited() {
+    let runtime = runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async {
+        // Make the runtime create its signal pipe and register some
+        // sockets with its epoll instance.
+        let _signals = signal(SignalKind::user_defined1()).unwrap();
+        let (a, _b) = UnixStream::pair().unwrap();
+
+        // Pretend that the runtime forgot FD_CLOEXEC on everything.
+        let runtime_fds: Vec<RawFd> = open_fds().into_iter().filter(|&fd| fd > 2).collect();
+        assert!(runtime_fds.len() >= 3, "{:?}", runtime_fds);
+        for &fd in &runtime_fds {
+            assert_ne!(unsafe { libc::fcntl(fd, libc::F_SETFD, 0) }, -1);
+        }
+
+        // Without closefds, the child inherits all of them.
+        let output = Command::new(env!("CARGO_BIN_EXE_list_fds"))
+            .output()
+            .await
+            .unwrap();
+        let leaked = parse_fds(output.stdout);
+        assert!(
+            runtime_fds.iter().all(|fd| leaked.contains(fd)),
+            "{:?} {:?}",
+            runtime_fds,
+            leaked
+        );
+
+        let kept = unsafe { OwnedFd::from_raw_fd(libc::dup(a.as_raw_fd())) };
+        let output = Command::new(env!("CARGO_BIN_EXE_list_fds"))
+            .stdout(Stdio::piped())
+            .close_fds()
+            .inherit_fd_as(&kept, 3000)
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(parse_fds(output.stdout), vec![0, 1, 2, 3000]);
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_list_fds"))
+            .stdout(Stdio::null())
+            .close_fds()
+            .spawn()
+            .unwrap();
+        assert!(child.wait().await.unwrap().success());
+
+        // The same Command can be spawned again.
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_list_fds"));
+        cmd.stdout(Stdio::null());
+        assert!(cmd.close_fds().status().await.unwrap().success());
+        // The Command is used up.
+        let err = cmd.close_fds().status().await.unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+
+        let status = Command::new("/nonexistent/program")
+            .close_fds()
+            .status()
+            .await;
+        assert_eq!(status.unwrap_err().kind(), io::ErrorKind::NotFound);
+    });
+}